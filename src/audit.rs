@@ -0,0 +1,66 @@
+// Append-only JSON-lines audit log, one event per decision, for ingestion by a
+// SIEM.  Many sshd processes may write to the log at once, so each event is
+// written with a single append while holding an exclusive lock on the file.
+
+use rustc_serialize::json;
+use std::old_path::posix::{Path};
+use time;
+
+use fd;
+use fd::{Errno, Fd};
+use logger::{Logger};
+
+#[derive(RustcEncodable)]
+struct AuditEvent {
+    timestamp:   String,
+    phase:       &'static str,
+    service:     Option<String>,
+    user:        String,
+    ruser:       Option<String>,
+    rhost:       Option<String>,
+    tty:         Option<String>,
+    tozny_user:  Option<String>,
+    session_id:  Option<String>,
+    method:      Option<&'static str>,
+    outcome:     &'static str,
+    error_code:  i32,
+}
+
+/// Appends an event describing the outcome of `phase`.  Failure to write the
+/// audit log is reported to syslog, but does not change the outcome.
+pub fn record(path: &Path, log: &Logger, phase: &'static str, outcome: &'static str,
+              error_code: i32) {
+    let event = AuditEvent {
+        timestamp:  format!("{}", time::now_utc().rfc3339()),
+        phase:      phase,
+        service:    log.service.clone(),
+        user:       log.unix_user.clone(),
+        ruser:      log.ruser.clone(),
+        rhost:      log.rhost.clone(),
+        tty:        log.tty.clone(),
+        tozny_user: log.tozny_user(),
+        session_id: log.session_id(),
+        method:     log.method().map(|m| m.as_str()),
+        outcome:    outcome,
+        error_code: error_code,
+    };
+    let written = json::encode(&event)
+        .map_err(|e| format!("could not encode audit event: {:?}", e))
+        .and_then(|line| {
+            append_line(path, &line)
+            .map_err(|errno| format!("could not write audit log {}: errno {}",
+                                     path.display(), errno))
+        });
+    if let Err(msg) = written {
+        log.err(&msg);
+    }
+}
+
+fn append_line(path: &Path, line: &str) -> Result<(), Errno> {
+    let file = try!(Fd::open(path, fd::O_WRONLY | fd::O_APPEND | fd::O_CREAT | fd::O_NOFOLLOW,
+                             0o600));
+    try!(file.flock(fd::LOCK_EX));
+    let written = file.write_all(format!("{}\n", line).as_bytes());
+    let _ = file.flock(fd::LOCK_UN);
+    written
+}
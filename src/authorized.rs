@@ -0,0 +1,257 @@
+// Entries of `authorized_users`.  An entry is either a bare Tozny user id, or a
+// table that can also limit when, through which services, and from where the
+// user may log in:
+//
+//     [[authorized_users]]
+//     id       = "sid_c233df00c07b9"
+//     name     = "alice"
+//     expires  = "2026-12-31"
+//     services = ["sshd"]
+//     from     = ["10.0.0.0/8", "2001:db8::/32"]
+
+use std::fmt;
+use time;
+use toml;
+
+use cidr::{Cidr};
+use config;
+use config::{ConfigError};
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct AuthorizedUser {
+    pub id:   String,
+    name:     Option<String>,
+    expires:  Option<Expiry>,
+    services: Option<Vec<String>>,
+    from:     Option<Vec<Cidr>>,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+struct Expiry {
+    // As written in the configuration, for messages.
+    text: String,
+    // Seconds since the epoch at which the entry stops working.
+    at:   i64,
+}
+
+/// The circumstances of a login attempt that authorization may depend on.
+#[derive(Clone, Copy)]
+pub struct Attempt<'a> {
+    pub service: Option<&'a str>,
+    pub rhost:   Option<&'a str>,
+}
+
+/// Why a login was not authorized.
+#[derive(PartialEq, Debug, Clone)]
+pub enum Denial {
+    /// No entry names the Tozny user.
+    NotListed,
+    /// The entry for the user has expired.
+    Expired(String, String),
+    /// The entry for the user does not allow this service.
+    Service(String, Option<String>),
+    /// The entry for the user does not allow logins from this host.
+    Source(String, Option<String>),
+}
+
+impl AuthorizedUser {
+    pub fn from_value(value: &toml::Value) -> Result<AuthorizedUser, ConfigError> {
+        if let Some(id) = value.as_str() {
+            return Ok(AuthorizedUser::with_id(id))
+        }
+        let table = try!(value.as_table().ok_or_else(|| {
+            ConfigError::TypeError("String or Table", value.type_str())
+        }));
+        let id       = try!(config::get(table, "id").and_then(config::as_str));
+        let name     = try!(config::get_opt(table, "name", config::as_str));
+        let expires  = try!(config::get_opt(table, "expires", as_expiry));
+        let services = try!(config::get_opt(table, "services", config::as_names));
+        let from     = try!(config::get_opt(table, "from", config::as_ranges));
+        Ok(AuthorizedUser {
+            id:       id.to_string(),
+            name:     name.map(|n| n.to_string()),
+            expires:  expires,
+            services: services,
+            from:     from,
+        })
+    }
+
+    /// An entry without restrictions.
+    pub fn with_id(id: &str) -> AuthorizedUser {
+        AuthorizedUser {
+            id:       id.to_string(),
+            name:     None,
+            expires:  None,
+            services: None,
+            from:     None,
+        }
+    }
+
+    /// Checks the restrictions of this entry, which must be for the Tozny user
+    /// that is logging in.  A restriction on services or hosts fails if the
+    /// application did not say which service or host this is.
+    pub fn check(&self, attempt: Attempt) -> Result<(), Denial> {
+        let label = self.name.clone().unwrap_or(self.id.clone());
+        if let Some(ref expires) = self.expires {
+            if time::get_time().sec >= expires.at {
+                return Err(Denial::Expired(label, expires.text.clone()))
+            }
+        }
+        if let Some(ref services) = self.services {
+            if !attempt.service.map_or(false, |s| services.iter().any(|a| a == s)) {
+                return Err(Denial::Service(label, attempt.service.map(|s| s.to_string())))
+            }
+        }
+        if let Some(ref from) = self.from {
+            if !attempt.rhost.map_or(false, |h| from.iter().any(|r| r.contains(h))) {
+                return Err(Denial::Source(label, attempt.rhost.map(|h| h.to_string())))
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Authorizes `user_id` if any entry for that user allows this attempt.
+/// Otherwise reports why the first entry for the user did not.
+pub fn check(entries: &[AuthorizedUser], user_id: &str, attempt: Attempt) -> Result<(), Denial> {
+    let mut denial = None;
+    for entry in entries.iter().filter(|e| e.id == user_id) {
+        match entry.check(attempt) {
+            Ok(())  => return Ok(()),
+            Err(d)  => if denial.is_none() { denial = Some(d) },
+        }
+    }
+    Err(denial.unwrap_or(Denial::NotListed))
+}
+
+pub fn as_authorized_users(v: &toml::Value) -> Result<Vec<AuthorizedUser>, ConfigError> {
+    let entries = try!(config::as_slice(v));
+    entries.iter().map(AuthorizedUser::from_value).collect()
+}
+
+// An expiry is a date, which allows logins through the end of that day (UTC),
+// or a date and time.  TOML date-times are accepted as well as strings.
+fn as_expiry(v: &toml::Value) -> Result<Expiry, ConfigError> {
+    let text = try!(match v {
+        &toml::Value::Datetime(ref d) => Ok(d.as_slice()),
+        _                             => config::as_str(v),
+    });
+    let at = match time::strptime(text, "%Y-%m-%dT%H:%M:%SZ") {
+        Ok(tm) => tm.to_timespec().sec,
+        Err(_) => {
+            let day = try!(time::strptime(text, "%Y-%m-%d").map_err(|_| {
+                ConfigError::InvalidValue("expires".to_string(), text.to_string())
+            }));
+            day.to_timespec().sec + 24 * 60 * 60
+        }
+    };
+    Ok(Expiry { text: text.to_string(), at: at })
+}
+
+impl fmt::Display for Denial {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &Denial::NotListed => {
+                f.write_str("not listed in authorized_users")
+            }
+            &Denial::Expired(ref user, ref date) => {
+                f.write_fmt(format_args!("access for {} expired at {}", user, date))
+            }
+            &Denial::Service(ref user, ref service) => {
+                f.write_fmt(format_args!("{} may not log in through service {}",
+                                         user, service.as_ref().map_or("(unknown)", |s| s)))
+            }
+            &Denial::Source(ref user, ref rhost) => {
+                f.write_fmt(format_args!("{} may not log in from {}",
+                                         user, rhost.as_ref().map_or("(unknown host)", |h| h)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use toml;
+
+    use super::{as_authorized_users, check, Attempt, AuthorizedUser, Denial};
+
+    fn entries(input: &str) -> Vec<AuthorizedUser> {
+        let table = toml::Parser::new(input).parse().expect("invalid TOML");
+        as_authorized_users(table.get("authorized_users").unwrap()).unwrap()
+    }
+
+    fn attempt<'a>(service: &'a str, rhost: &'a str) -> Attempt<'a> {
+        Attempt { service: Some(service), rhost: Some(rhost) }
+    }
+
+    #[test]
+    fn bare_ids_have_no_restrictions() {
+        let users = entries(r#"authorized_users = ["sid_alice"]"#);
+        assert_eq!(users, vec![AuthorizedUser::with_id("sid_alice")]);
+        assert_eq!(check(&users, "sid_alice", Attempt { service: None, rhost: None }), Ok(()));
+        assert_eq!(check(&users, "sid_bob", attempt("sshd", "10.0.0.1")),
+                   Err(Denial::NotListed));
+    }
+
+    #[test]
+    fn restricts_services_and_sources() {
+        let users = entries(r#"
+            [[authorized_users]]
+            id       = "sid_alice"
+            name     = "alice"
+            services = ["sshd"]
+            from     = ["10.0.0.0/8"]
+        "#);
+        assert_eq!(check(&users, "sid_alice", attempt("sshd", "10.1.2.3")), Ok(()));
+        assert_eq!(check(&users, "sid_alice", attempt("login", "10.1.2.3")),
+                   Err(Denial::Service("alice".to_string(), Some("login".to_string()))));
+        assert_eq!(check(&users, "sid_alice", attempt("sshd", "192.0.2.1")),
+                   Err(Denial::Source("alice".to_string(), Some("192.0.2.1".to_string()))));
+        assert_eq!(check(&users, "sid_alice", Attempt { service: Some("sshd"), rhost: None }),
+                   Err(Denial::Source("alice".to_string(), None)));
+    }
+
+    #[test]
+    fn expired_entries_deny() {
+        let users = entries(r#"
+            [[authorized_users]]
+            id      = "sid_alice"
+            expires = "2001-01-01"
+
+            [[authorized_users]]
+            id      = "sid_bob"
+            expires = 2999-01-01T00:00:00Z
+        "#);
+        assert_eq!(check(&users, "sid_alice", attempt("sshd", "10.1.2.3")),
+                   Err(Denial::Expired("sid_alice".to_string(), "2001-01-01".to_string())));
+        assert_eq!(check(&users, "sid_bob", attempt("sshd", "10.1.2.3")), Ok(()));
+    }
+
+    #[test]
+    fn any_matching_entry_allows() {
+        let users = entries(r#"
+            [[authorized_users]]
+            id       = "sid_alice"
+            services = ["sshd"]
+
+            [[authorized_users]]
+            id       = "sid_alice"
+            services = ["sudo"]
+        "#);
+        assert_eq!(check(&users, "sid_alice", attempt("sudo", "10.1.2.3")), Ok(()));
+    }
+
+    #[test]
+    fn rejects_invalid_entries() {
+        for entry in [r#"name = "alice""#,
+                      r#"id = "sid_alice"
+                         expires = "soon""#,
+                      r#"id = "sid_alice"
+                         from = ["10.0.0.0/40"]"#].iter() {
+            let input = format!("[[authorized_users]]\n{}", entry);
+            let table = toml::Parser::new(&input).parse().expect("invalid TOML");
+            assert!(as_authorized_users(table.get("authorized_users").unwrap()).is_err(),
+                    "accepted {}", entry);
+        }
+    }
+}
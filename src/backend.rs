@@ -0,0 +1,143 @@
+// The parts of the Tozny user API that the module depends on, behind a trait,
+// so that the authentication flow can be run against something other than the
+// real API.
+
+use std::cell::{RefCell};
+use std::collections::{VecDeque};
+use tozny_auth::login::{Login};
+use tozny_auth::protocol::{Newtype, Presence, SessionId};
+use tozny_auth::question;
+use tozny_auth::question::{QuestionError};
+use tozny_auth::user::{LoginChallenge, UserApi};
+
+#[cfg(test)]
+use rustc_serialize::json;
+
+/// A login challenge, with what the module needs to ask the user to approve
+/// it.
+pub struct Challenge {
+    pub session_id: SessionId,
+    pub presence:   Presence,
+    pub qr_url:     String,
+    pub mobile_url: String,
+}
+
+impl Challenge {
+    fn from_login_challenge(challenge: LoginChallenge) -> Challenge {
+        Challenge {
+            session_id: challenge.session_id,
+            presence:   challenge.presence,
+            qr_url:     challenge.qr_url.to_string(),
+            mobile_url: challenge.mobile_url.to_string(),
+        }
+    }
+}
+
+pub trait Backend {
+    /// Starts a new login session.
+    fn login_challenge(&self) -> Result<Challenge, QuestionError>;
+
+    /// Asks the phone behind `presence` to approve the session.
+    fn push(&self, session_id: &SessionId, presence: &Presence) -> Result<(), QuestionError>;
+
+    /// Returns the signed login once the user has approved the session.
+    fn check_session_status(&self, session_id: &SessionId
+                            ) -> Result<Option<String>, QuestionError>;
+
+    /// Verifies and decodes a signed login.
+    fn unpack(&self, signed_data: &str) -> Result<Login, QuestionError>;
+}
+
+impl Backend for UserApi {
+    fn login_challenge(&self) -> Result<Challenge, QuestionError> {
+        UserApi::login_challenge(self).map(Challenge::from_login_challenge)
+    }
+
+    fn push(&self, session_id: &SessionId, presence: &Presence) -> Result<(), QuestionError> {
+        UserApi::push(self, session_id, presence).map(|_| ())
+    }
+
+    fn check_session_status(&self, session_id: &SessionId
+                            ) -> Result<Option<String>, QuestionError> {
+        UserApi::check_session_status(self, session_id)
+        .map(|status| status.map(|question| question.signed_data))
+    }
+
+    fn unpack(&self, signed_data: &str) -> Result<Login, QuestionError> {
+        question::unpack::<Login>(signed_data)
+    }
+}
+
+/// A scripted stand-in for the Tozny API.  Each status check takes the next
+/// scripted answer; once the script runs out, the session is never approved.
+///
+/// Logins are not signed: the "signed data" is the JSON encoding of the login,
+/// and anything that does not decode counts as a bad signature.
+#[cfg(test)]
+pub struct FakeBackend {
+    session_id: String,
+    statuses:   RefCell<VecDeque<Result<Option<String>, QuestionError>>>,
+    pushes:     RefCell<Vec<String>>,
+}
+
+#[cfg(test)]
+impl FakeBackend {
+    pub fn new(session_id: &str) -> FakeBackend {
+        FakeBackend {
+            session_id: session_id.to_string(),
+            statuses:   RefCell::new(VecDeque::new()),
+            pushes:     RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Leaves the next `polls` status checks unanswered, then answers with
+    /// `signed_data`.
+    pub fn approve_after(&self, polls: usize, signed_data: &str) {
+        self.leave_pending(polls);
+        self.statuses.borrow_mut().push_back(Ok(Some(signed_data.to_string())));
+    }
+
+    /// Leaves the next `polls` status checks that have not been scripted yet
+    /// unanswered.
+    pub fn leave_pending(&self, polls: usize) {
+        let mut statuses = self.statuses.borrow_mut();
+        for _ in 0..polls {
+            statuses.push_back(Ok(None));
+        }
+    }
+
+    /// Fails the next status check that has not been scripted yet.
+    pub fn fail_with(&self, err: QuestionError) {
+        self.statuses.borrow_mut().push_back(Err(err));
+    }
+
+    /// Presence tokens that push notifications were sent to.
+    pub fn pushes(&self) -> Vec<String> {
+        self.pushes.borrow().clone()
+    }
+}
+
+#[cfg(test)]
+impl Backend for FakeBackend {
+    fn login_challenge(&self) -> Result<Challenge, QuestionError> {
+        Ok(Challenge {
+            session_id: SessionId::new(self.session_id.clone()),
+            presence:   Presence::new(format!("presence-{}", self.session_id)),
+            qr_url:     format!("https://fake.invalid/qr/{}", self.session_id),
+            mobile_url: format!("tozny://fake.invalid/login/{}", self.session_id),
+        })
+    }
+
+    fn push(&self, _: &SessionId, presence: &Presence) -> Result<(), QuestionError> {
+        self.pushes.borrow_mut().push(presence.as_slice().to_string());
+        Ok(())
+    }
+
+    fn check_session_status(&self, _: &SessionId) -> Result<Option<String>, QuestionError> {
+        self.statuses.borrow_mut().pop_front().unwrap_or(Ok(None))
+    }
+
+    fn unpack(&self, signed_data: &str) -> Result<Login, QuestionError> {
+        json::decode(signed_data).map_err(|_| QuestionError::InvalidSignature)
+    }
+}
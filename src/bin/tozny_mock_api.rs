@@ -0,0 +1,293 @@
+// A stand-in for the Tozny user API, for testing the built module on a machine
+// without network access.  It answers the three calls that the module makes
+// (`user.login_challenge`, `user.push`, and `user.check_session_status`) on
+// localhost, and the outcome of every login is scripted on the command line.
+//
+// Point `api_url` at it:
+//
+//     $ tozny_mock_api --port 18080 --outcome approve --polls 3
+//     api_url = "http://127.0.0.1:18080/index.php"
+//
+// Requests are handled one at a time, which is plenty for a test.
+//
+// The user API does not give the module the realm secret, so the module does
+// not verify the signature on a login; it relies on TLS to the API instead.
+// The mock therefore sends a placeholder signature, and has no outcome for a
+// badly signed login.
+
+#![feature(old_io)]
+#![feature(env)]
+
+extern crate getopts;
+extern crate rand;
+extern crate rustc_serialize;
+extern crate time;
+extern crate url;
+
+use getopts::{Matches, Options};
+use rand::Rng;
+use rustc_serialize::base64;
+use rustc_serialize::base64::{ToBase64};
+use rustc_serialize::hex::{ToHex};
+use rustc_serialize::json;
+use std::ascii::{AsciiExt};
+use std::collections::{HashMap};
+use std::env;
+use std::old_io::{Acceptor, Buffer, BufferedReader, IoResult, Listener, Reader, Writer};
+use std::old_io::net::tcp::{TcpListener, TcpStream};
+
+const DEFAULT_PORT: u16 = 18080;
+
+// Seconds until an approved login expires.
+const LOGIN_LIFETIME: i64 = 300;
+
+/// What happens once a session has been polled `polls` times.
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum Outcome {
+    /// Answer with a login.
+    Approve,
+    /// Answer with an error, as when the user denies the login on the phone.
+    Deny,
+    /// Stay pending forever.
+    Never,
+    /// Answer with data in place of a login that is not a login.
+    Malformed,
+}
+
+impl Outcome {
+    fn from_name(name: &str) -> Option<Outcome> {
+        match name {
+            "approve"       => Some(Outcome::Approve),
+            "deny"          => Some(Outcome::Deny),
+            "never"         => Some(Outcome::Never),
+            "malformed"     => Some(Outcome::Malformed),
+            _               => None,
+        }
+    }
+}
+
+struct Script {
+    port:         u16,
+    outcome:      Outcome,
+    polls:        usize,
+    realm_key_id: String,
+    user_id:      String,
+    user_display: String,
+}
+
+#[derive(RustcEncodable)]
+struct LoginChallenge {
+    _return:      &'static str,
+    challenge:    String,
+    realm_key_id: String,
+    session_id:   String,
+    qr_url:       String,
+    mobile_url:   String,
+    presence:     String,
+}
+
+#[derive(RustcEncodable)]
+struct SignedLogin {
+    user_id:        String,
+    user_display:   String,
+    session_id:     String,
+    realm_key_id:   String,
+    expires_at:     i64,
+    signature_type: &'static str,
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    match parse_script(&args[1..]) {
+        Ok(script) => {
+            if let Err(e) = serve(&script) {
+                println!("tozny_mock_api: {}", e);
+                env::set_exit_status(1);
+            }
+        }
+        Err(msg) => {
+            println!("tozny_mock_api: {}\n\n{}", msg,
+                     program_opts().usage("Usage: tozny_mock_api [options]"));
+            env::set_exit_status(2);
+        }
+    }
+}
+
+fn program_opts() -> Options {
+    let mut opts = Options::new();
+    opts.optopt("", "port", "port to listen on, on 127.0.0.1", "PORT");
+    opts.optopt("", "outcome",
+                "approve, deny, never, or malformed (default: approve)", "OUTCOME");
+    opts.optopt("", "polls", "status checks that stay pending before the outcome (default: 0)",
+                "N");
+    opts.optopt("", "realm-key-id", "realm key id to hand out in challenges", "ID");
+    opts.optopt("", "user-id", "Tozny user id of the approving user", "ID");
+    opts.optopt("", "user-display", "display name of the approving user", "NAME");
+    opts
+}
+
+fn parse_script(args: &[String]) -> Result<Script, String> {
+    let matches = try!(program_opts().parse(args).map_err(|e| format!("{}", e)));
+    let outcome = try!(opt_or(&matches, "outcome", "approve", |s| Outcome::from_name(s)));
+    Ok(Script {
+        port:         try!(opt_or(&matches, "port", &DEFAULT_PORT.to_string(),
+                                  |s| s.parse().ok())),
+        outcome:      outcome,
+        polls:        try!(opt_or(&matches, "polls", "0", |s| s.parse().ok())),
+        realm_key_id: matches.opt_str("realm-key-id").unwrap_or("sid_mock_realm".to_string()),
+        user_id:      matches.opt_str("user-id").unwrap_or("sid_mock_user".to_string()),
+        user_display: matches.opt_str("user-display").unwrap_or("Mock User".to_string()),
+    })
+}
+
+fn opt_or<T, F>(matches: &Matches, name: &str, default: &str, parse: F) -> Result<T, String>
+        where F: Fn(&str) -> Option<T> {
+    let value = matches.opt_str(name).unwrap_or(default.to_string());
+    parse(&value).ok_or_else(|| format!("invalid value for --{}: {}", name, value))
+}
+
+fn serve(script: &Script) -> IoResult<()> {
+    let listener = try!(TcpListener::bind(("127.0.0.1", script.port)));
+    let mut acceptor = try!(listener.listen());
+    println!("listening on http://127.0.0.1:{}/ with outcome {:?} after {} polls",
+             script.port, script.outcome, script.polls);
+
+    // Status checks seen so far, by session id.
+    let mut sessions: HashMap<String, usize> = HashMap::new();
+    for stream in acceptor.incoming() {
+        let result = stream.and_then(|mut stream| {
+            let params = try!(read_request(&mut stream));
+            let body = respond(script, &mut sessions, &params);
+            write_response(&mut stream, &body)
+        });
+        if let Err(e) = result {
+            println!("request failed: {}", e);
+        }
+    }
+    Ok(())
+}
+
+// Reads an HTTP request, and returns the parameters from both the query string
+// and a form-encoded body.
+fn read_request(stream: &mut TcpStream) -> IoResult<HashMap<String, String>> {
+    let mut reader = BufferedReader::new(stream.clone());
+    let request_line = try!(reader.read_line());
+    let mut content_length = 0;
+    loop {
+        let header = try!(reader.read_line());
+        let header = header.trim();
+        if header.is_empty() { break }
+        if let Some(i) = header.find(':') {
+            if header[..i].trim().to_ascii_lowercase() == "content-length" {
+                content_length = header[i + 1..].trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    let body = try!(reader.read_exact(content_length));
+
+    let target = request_line.split(' ').nth(1).unwrap_or("");
+    let query  = target.find('?').map(|i| &target[i + 1..]).unwrap_or("");
+    let mut params = HashMap::new();
+    for (key, value) in url::form_urlencoded::parse(query.as_bytes()).into_iter()
+                        .chain(url::form_urlencoded::parse(&body).into_iter()) {
+        params.insert(key, value);
+    }
+    Ok(params)
+}
+
+fn write_response(stream: &mut TcpStream, body: &str) -> IoResult<()> {
+    stream.write_str(&format!(
+        "HTTP/1.0 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+        Connection: close\r\n\r\n{}",
+        body.len(), body))
+}
+
+fn respond(script: &Script, sessions: &mut HashMap<String, usize>,
+           params: &HashMap<String, String>) -> String {
+    let method     = params.get("method").map(|m| m.as_slice()).unwrap_or("");
+    let session_id = params.get("session_id").cloned().unwrap_or(String::new());
+    println!("{} {}", method, session_id);
+    match method {
+        "user.login_challenge"       => login_challenge(script, sessions),
+        "user.push"                  => r#"{"return":"ok"}"#.to_string(),
+        "user.check_session_status"  => {
+            match sessions.get_mut(&session_id) {
+                Some(polls) => {
+                    *polls += 1;
+                    session_status(script, &session_id, *polls)
+                }
+                None => error("invalid_session", "No such session"),
+            }
+        }
+        _ => error("invalid_method", &format!("Unsupported method: {}", method)),
+    }
+}
+
+fn login_challenge(script: &Script, sessions: &mut HashMap<String, usize>) -> String {
+    let session_id = random_hex(16);
+    sessions.insert(session_id.clone(), 0);
+    let challenge = LoginChallenge {
+        _return:      "ok",
+        challenge:    random_hex(32),
+        realm_key_id: script.realm_key_id.clone(),
+        session_id:   session_id.clone(),
+        qr_url:       format!("http://127.0.0.1:{}/qr/{}", script.port, session_id),
+        mobile_url:   format!("tozny://127.0.0.1:{}/login/{}", script.port, session_id),
+        presence:     random_hex(16),
+    };
+    rename_return(json::encode(&challenge).unwrap())
+}
+
+fn session_status(script: &Script, session_id: &str, polls: usize) -> String {
+    if polls <= script.polls || script.outcome == Outcome::Never {
+        return r#"{"return":"ok","status":"pending"}"#.to_string()
+    }
+    match script.outcome {
+        Outcome::Deny         => error("login_denied", "The user denied the login"),
+        Outcome::Malformed    => signed("this is not a login".as_bytes().to_base64(
+                                            base64::URL_SAFE)),
+        _                     => signed(encode_login(script, session_id)),
+    }
+}
+
+fn encode_login(script: &Script, session_id: &str) -> String {
+    let login = SignedLogin {
+        user_id:        script.user_id.clone(),
+        user_display:   script.user_display.clone(),
+        session_id:     session_id.to_string(),
+        realm_key_id:   script.realm_key_id.clone(),
+        expires_at:     time::get_time().sec + LOGIN_LIFETIME,
+        signature_type: "HMAC",
+    };
+    json::encode(&login).unwrap().as_bytes().to_base64(base64::URL_SAFE)
+}
+
+// Wraps `signed_data` in a status response, with a placeholder signature.
+fn signed(signed_data: String) -> String {
+    let signature = "mock_signature".to_string();
+    let mut fields = json::Object::new();
+    fields.insert("return".to_string(), json::Json::String("ok".to_string()));
+    fields.insert("signed_data".to_string(), json::Json::String(signed_data));
+    fields.insert("signature".to_string(), json::Json::String(signature));
+    json::Json::Object(fields).to_string()
+}
+
+fn error(code: &str, message: &str) -> String {
+    let mut err = json::Object::new();
+    err.insert("error_code".to_string(), json::Json::String(code.to_string()));
+    err.insert("error_message".to_string(), json::Json::String(message.to_string()));
+    let mut fields = json::Object::new();
+    fields.insert("return".to_string(), json::Json::String("error".to_string()));
+    fields.insert("errors".to_string(), json::Json::Array(vec![json::Json::Object(err)]));
+    json::Json::Object(fields).to_string()
+}
+
+// `return` is a keyword, so the field is declared as `_return`.
+fn rename_return(encoded: String) -> String {
+    encoded.replace("\"_return\":", "\"return\":")
+}
+
+fn random_hex(bytes: usize) -> String {
+    let mut rng = rand::thread_rng();
+    (0..bytes).map(|_| rng.gen::<u8>()).collect::<Vec<u8>>().to_hex()
+}
@@ -0,0 +1,191 @@
+// IPv4 and IPv6 address ranges in CIDR notation, such as `10.0.0.0/8` or
+// `2001:db8::/32`, for restricting where a login may come from.  A bare address
+// is a range of one.
+//
+// IPv4 addresses written as IPv4-mapped IPv6 addresses (`::ffff:10.1.2.3`), as
+// sshd reports them on dual-stack sockets, match IPv4 ranges.
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Cidr {
+    addr:   Vec<u8>,
+    prefix: usize,
+}
+
+impl Cidr {
+    pub fn parse(s: &str) -> Option<Cidr> {
+        let (addr, prefix) = match s.find('/') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None    => (s, None),
+        };
+        parse_addr(addr).and_then(|addr| {
+            let bits = addr.len() * 8;
+            let prefix = match prefix {
+                Some(p) => match p.parse::<usize>() {
+                    Ok(p) if p <= bits => p,
+                    _                  => return None,
+                },
+                None => bits,
+            };
+            Some(Cidr { addr: addr, prefix: prefix })
+        })
+    }
+
+    /// Whether `addr` is in this range.  Host names never match.
+    pub fn contains(&self, addr: &str) -> bool {
+        match parse_addr(addr) {
+            Some(ref a) if a.len() == self.addr.len() => {
+                let full = self.prefix / 8;
+                let rest = self.prefix % 8;
+                if a[..full] != self.addr[..full] {
+                    return false
+                }
+                if rest == 0 {
+                    return true
+                }
+                let mask = 0xffu8 << (8 - rest);
+                a[full] & mask == self.addr[full] & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// Returns 4 bytes for an IPv4 address, including an IPv4-mapped IPv6 address,
+// or 16 bytes for an IPv6 address.
+fn parse_addr(s: &str) -> Option<Vec<u8>> {
+    if s.contains(":") {
+        parse_v6(s).map(|bytes| {
+            if bytes[..10].iter().all(|b| *b == 0) && bytes[10] == 0xff && bytes[11] == 0xff {
+                bytes[12..].to_vec()
+            }
+            else {
+                bytes
+            }
+        })
+    }
+    else {
+        parse_v4(s)
+    }
+}
+
+fn parse_v4(s: &str) -> Option<Vec<u8>> {
+    let parts: Vec<&str> = s.split('.').collect();
+    if parts.len() != 4 {
+        return None
+    }
+    parts.iter().map(|p| {
+        if p.is_empty() || p.len() > 3 || !p.chars().all(|c| c.is_digit(10)) {
+            None
+        }
+        else {
+            p.parse::<u16>().ok().and_then(|n| if n <= 255 { Some(n as u8) } else { None })
+        }
+    })
+    .collect()
+}
+
+fn parse_v6(s: &str) -> Option<Vec<u8>> {
+    let (head, tail) = match s.find("::") {
+        Some(i) => (&s[..i], Some(&s[i + 2..])),
+        None    => (s, None),
+    };
+    let mut head_bytes = match parse_groups(head) { Some(b) => b, None => return None };
+    match tail {
+        None => {
+            if head_bytes.len() == 16 { Some(head_bytes) } else { None }
+        }
+        Some(tail) => {
+            let tail_bytes = match parse_groups(tail) { Some(b) => b, None => return None };
+            // "::" stands for at least one group of zeros.
+            if head_bytes.len() + tail_bytes.len() > 14 {
+                return None
+            }
+            let zeros = 16 - head_bytes.len() - tail_bytes.len();
+            head_bytes.extend(::std::iter::repeat(0u8).take(zeros));
+            head_bytes.extend(tail_bytes.into_iter());
+            Some(head_bytes)
+        }
+    }
+}
+
+// Parses colon-separated groups of hex digits, the last of which may be an
+// IPv4 address.
+fn parse_groups(s: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    if s.is_empty() {
+        return Some(bytes)
+    }
+    let groups: Vec<&str> = s.split(':').collect();
+    for (i, group) in groups.iter().enumerate() {
+        if i == groups.len() - 1 && group.contains(".") {
+            match parse_v4(group) {
+                Some(v4) => bytes.extend(v4.into_iter()),
+                None     => return None,
+            }
+        }
+        else {
+            if group.is_empty() || group.len() > 4 {
+                return None
+            }
+            let mut n: u32 = 0;
+            for c in group.chars() {
+                match c.to_digit(16) {
+                    Some(d) => n = n * 16 + d,
+                    None    => return None,
+                }
+            }
+            bytes.push((n >> 8) as u8);
+            bytes.push(n as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cidr;
+
+    fn contains(range: &str, addr: &str) -> bool {
+        Cidr::parse(range).expect("invalid range").contains(addr)
+    }
+
+    #[test]
+    fn matches_ipv4_ranges() {
+        assert!(contains("10.0.0.0/8", "10.200.3.4"));
+        assert!(!contains("10.0.0.0/8", "11.0.0.1"));
+        assert!(contains("192.168.4.0/22", "192.168.7.255"));
+        assert!(!contains("192.168.4.0/22", "192.168.8.0"));
+        assert!(contains("203.0.113.7", "203.0.113.7"));
+        assert!(!contains("203.0.113.7", "203.0.113.8"));
+        assert!(contains("0.0.0.0/0", "198.51.100.1"));
+    }
+
+    #[test]
+    fn matches_ipv6_ranges() {
+        assert!(contains("2001:db8::/32", "2001:db8:1::5"));
+        assert!(!contains("2001:db8::/32", "2001:db9::5"));
+        assert!(contains("::1", "0:0:0:0:0:0:0:1"));
+        assert!(contains("fe80::/10", "febf::1"));
+        assert!(!contains("fe80::/10", "fec0::1"));
+    }
+
+    #[test]
+    fn matches_mapped_ipv4_against_ipv4_ranges() {
+        assert!(contains("10.0.0.0/8", "::ffff:10.1.2.3"));
+        assert!(!contains("2001:db8::/32", "::ffff:10.1.2.3"));
+    }
+
+    #[test]
+    fn host_names_never_match() {
+        assert!(!contains("10.0.0.0/8", "host.example.com"));
+        assert!(!contains("10.0.0.0/8", ""));
+    }
+
+    #[test]
+    fn rejects_invalid_ranges() {
+        for range in ["10.0.0.0/33", "10.0.0/8", "256.0.0.1", "10.0.0.0/", "1::2::3", "12345::",
+                      "1:2:3:4:5:6:7:8:9", "example.com"].iter() {
+            assert!(Cidr::parse(range).is_none(), "accepted {}", range);
+        }
+    }
+}
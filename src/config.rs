@@ -1,9 +1,11 @@
 use getopts;
 use getopts::Options;
+use libc::{geteuid, mode_t, uid_t};
 use std;
 use std::clone::Clone;
 use std::fmt;
-use std::old_io::{Command, File, Reader};
+use std::time::Duration;
+use std::old_io::{fs, File, IoError, Reader};
 use std::old_path::{GenericPath};
 use std::old_io::fs::{PathExtensions};
 use std::old_path::posix::{Path};
@@ -12,90 +14,556 @@ use tozny_auth::{Login, UserApi};
 use tozny_auth::protocol::{KeyId, Newtype};
 use url;
 
+use authorized;
+use authorized::{Attempt, AuthorizedUser, Denial};
+use cidr::{Cidr};
+use fd;
+use passwd;
+use passwd::{Passwd};
+use policy;
+use policy::{Rule};
+use user_map::{UserMap};
+
+const DEFAULT_API_URL: &'static str = "https://api.tozny.com";
+
+// Seconds to wait for the user to approve a login.
+const DEFAULT_TIMEOUT: i64 = 100;
+
+// Seconds between checks of the login session status.
+const DEFAULT_POLL_INTERVAL: i64 = 1;
+
+// Seconds to wait for an answer to a push notification before showing a QR
+// code.
+const DEFAULT_PUSH_GRACE: i64 = 30;
+
+// Longest accepted time setting, in seconds: one day.  Much larger values
+// overflow the arithmetic on durations while polling.
+const MAX_SECONDS: i64 = 24 * 60 * 60;
+
+// Root-owned configuration that applies to every user.
+const SYSTEM_CONFIG: &'static str = "/etc/tozny/toznyauth.toml";
+
+// Root-owned mapping of Tozny identities to unix accounts.
+const USERS_FILE: &'static str = "/etc/tozny/users.toml";
+
+// Root-owned per-user configuration files, named `<user>.toml`.
+const AUTHORIZED_DIR: &'static str = "/etc/tozny/authorized.d";
 
 #[derive(PartialEq, Debug)]
 pub struct Config {
-    realm_key_id:     KeyId,
-    api_url:          url::Url,
-    authorized_users: toml::Array,
-    pub home_dir:     Path,
-    pub presence:     bool,
-    pub prompt:       bool,
-    pub qr:           bool,
-    pub mobile_url:   bool,
+    realm_key_id:         KeyId,
+    api_url:              url::Url,
+    authorized_users:     Vec<AuthorizedUser>,
+    authorized_groups:    Vec<String>,
+    user_map:             UserMap,
+    use_home:             bool,
+    strict_modes:         bool,
+    allowed_targets:      Option<Vec<String>>,
+    pub account:          Passwd,
+    pub debug:            bool,
+    pub presence:         bool,
+    pub presence_as_user: bool,
+    pub prompt:           bool,
+    pub qr_style:         QrStyle,
+    pub mobile_url:       bool,
+    pub timeout:          Duration,
+    pub poll_interval:    Duration,
+    pub push_grace:       Duration,
+}
+
+/// How the QR code for a login challenge is displayed.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum QrStyle {
+    /// QR code drawn with Unicode block characters
+    Unicode,
+    /// QR code drawn with terminal color codes, for terminals with poor
+    /// Unicode support
+    Ascii,
+    /// No QR code, only a URL to a page that displays it
+    Url,
+}
+
+impl QrStyle {
+    fn from_name(name: &str) -> Option<QrStyle> {
+        match name {
+            "unicode" => Some(QrStyle::Unicode),
+            "ascii"   => Some(QrStyle::Ascii),
+            "url"     => Some(QrStyle::Url),
+            _         => None,
+        }
+    }
+}
+
+/// What to do when Tozny cannot be used to authenticate the user: when the API
+/// cannot be reached, or when the account has no per-user configuration.  A
+/// user who does not respond in time is not covered, so that waiting cannot get
+/// around Tozny.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum OnUnavailable {
+    /// Fail with `PAM_AUTH_ERR`.
+    Deny,
+    /// Return `PAM_IGNORE`, so that the other modules in the stack decide.
+    Ignore,
+    /// Return `PAM_SUCCESS`, as if the user had authenticated.
+    Success,
+}
+
+impl OnUnavailable {
+    fn from_name(name: &str) -> Option<OnUnavailable> {
+        match name {
+            "deny"    => Some(OnUnavailable::Deny),
+            "ignore"  => Some(OnUnavailable::Ignore),
+            "success" => Some(OnUnavailable::Success),
+            _         => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            OnUnavailable::Deny    => "deny",
+            OnUnavailable::Ignore  => "ignore",
+            OnUnavailable::Success => "success",
+        }
+    }
 }
 
 impl Config {
-    pub fn build(unix_user: &str, args: &[String]) -> Result<Config, ConfigError> {
-        program_opts().parse(args).map_err(ConfigError::GetoptsError)
-        .and_then(|opts| {
-            get_home(unix_user).ok_or(ConfigError::NoHomeDir)
-            .and_then(|home| {
-                let auth = get_auth_path(&home);
-                if auth.is_file() { Ok((home, auth)) }
-                    else { Err(ConfigError::MissingAuthFile(auth)) }
-            })
-            .and_then(|(home, auth)| {
-                read_config(&auth)
-                .map(|config_file| {
-                    Config {
-                        realm_key_id:     config_file.realm_key_id,
-                        api_url:          config_file.api_url,
-                        authorized_users: config_file.authorized_users,
-                        home_dir:         home,
-                        prompt:           opts.opt_present("prompt"),
-                        presence:         !opts.opt_present("no-presence"),
-                        qr:               !opts.opt_present("no-qr"),
-                        mobile_url:       !opts.opt_present("no-mobile"),
-                    }
-                })
-            })
+    // Settings come from, in order of precedence:
+    //
+    // 1. module arguments
+    // 2. the per-user file
+    // 3. the system-wide file
+    // 4. built-in defaults
+    //
+    // Module arguments and the system-wide file come from `trusted`.
+    pub fn build(unix_user: &str, trusted: &Trusted) -> Result<Config, ConfigError> {
+        let account = try!(passwd::lookup(unix_user).ok_or_else(|| {
+            ConfigError::NoSuchUser(unix_user.to_string())
+        }));
+
+        let use_home = trusted.setting(|s| s.home_config).unwrap_or(true);
+        let strict_modes = trusted.setting(|s| s.strict_modes).unwrap_or(true);
+        let users_path = trusted.setting(|s| s.users_file.clone())
+            .map(|p| Path::new(p))
+            .unwrap_or(Path::new(USERS_FILE));
+        let user_map = try!(UserMap::read(&users_path));
+        // Accounts in the central mapping do not need a per-user file.
+        let auth = match get_user_auth_path(unix_user, &account.home, use_home) {
+            Err(ConfigError::MissingAuthFile(_)) |
+            Err(ConfigError::NoHomeDir) if user_map.covers(unix_user) => None,
+            other => Some(try!(other)),
+        };
+        // A user must not be able to choose which accounts they may become
+        // with sudo or su.
+        let allowed_targets = trusted.setting(|s| s.allowed_targets.clone());
+        let user_file = match auth {
+            Some(ref auth) => {
+                if strict_modes {
+                    try!(check_permissions(auth, &account));
+                }
+                try!(read_config(auth))
+            }
+            None => ConfigFile::empty(),
+        };
+        let config_file = user_file.merge(trusted.system.clone());
+
+        let realm_key_id = try!(config_file.realm_key_id.ok_or_else(|| {
+            ConfigError::MissingField("realm_key_id".to_string())
+        }));
+        let authorized_groups = config_file.authorized_groups.unwrap_or(Vec::new());
+        let authorized_users = match config_file.authorized_users {
+            Some(users) => users,
+            None if user_map.covers(unix_user) || !authorized_groups.is_empty() => Vec::new(),
+            None => return Err(ConfigError::MissingField("authorized_users".to_string())),
+        };
+        let api_url = try!(config_file.api_url.map(Ok).unwrap_or_else(|| {
+            url::Url::parse(DEFAULT_API_URL).map_err(ConfigError::InvalidUrl)
+        }));
+        let settings = trusted.args.clone().merge(config_file.settings);
+
+        Ok(Config {
+            realm_key_id:      realm_key_id,
+            api_url:           api_url,
+            authorized_users:  authorized_users,
+            authorized_groups: authorized_groups,
+            user_map:          user_map,
+            use_home:          use_home,
+            strict_modes:      strict_modes,
+            allowed_targets:   allowed_targets,
+            account:           account,
+            debug:             settings.debug.unwrap_or(false),
+            prompt:            settings.prompt.unwrap_or(false),
+            presence:          settings.presence.unwrap_or(true),
+            presence_as_user:  settings.presence_as_user.unwrap_or(false),
+            qr_style:          settings.qr_style.unwrap_or(QrStyle::Unicode),
+            mobile_url:        settings.mobile_url.unwrap_or(true),
+            timeout:           Duration::seconds(settings.timeout.unwrap_or(DEFAULT_TIMEOUT)),
+            poll_interval:     Duration::seconds(
+                                   settings.poll_interval.unwrap_or(DEFAULT_POLL_INTERVAL)),
+            push_grace:        Duration::seconds(
+                                   settings.push_grace.unwrap_or(DEFAULT_PUSH_GRACE)),
         })
     }
 
+    /// Configuration for `account` with every setting at its default, for
+    /// tests that do not read any files.
+    #[cfg(test)]
+    pub fn for_test(realm_key_id: &str, authorized_users: &[&str], account: Passwd) -> Config {
+        Config {
+            realm_key_id:      KeyId::new(realm_key_id.to_string()),
+            api_url:           url::Url::parse(DEFAULT_API_URL).unwrap(),
+            authorized_users:  authorized_users.iter()
+                                   .map(|u| AuthorizedUser::with_id(u))
+                                   .collect(),
+            authorized_groups: Vec::new(),
+            user_map:          UserMap::empty(),
+            use_home:          true,
+            strict_modes:      true,
+            allowed_targets:   None,
+            account:           account,
+            debug:             false,
+            prompt:            false,
+            presence:          true,
+            presence_as_user:  false,
+            qr_style:          QrStyle::Unicode,
+            mobile_url:        true,
+            timeout:           Duration::seconds(DEFAULT_TIMEOUT),
+            poll_interval:     Duration::seconds(DEFAULT_POLL_INTERVAL),
+            push_grace:        Duration::seconds(DEFAULT_PUSH_GRACE),
+        }
+    }
+
+    /// Whether the user may become `target` with sudo or su.  The user's own
+    /// per-user file decides whose Tozny login is accepted, and su has no
+    /// sudoers behind it, so without an `allowed_targets` list the user may
+    /// only become their own account.
+    pub fn may_become(&self, target: &str) -> bool {
+        match self.allowed_targets {
+            Some(ref targets) => targets.iter().any(|t| t == "*" || t == target),
+            None              => target == self.account.name,
+        }
+    }
+
+    pub fn realm_key_id(&self) -> &KeyId {
+        &self.realm_key_id
+    }
+
     pub fn get_user_api(&self) -> UserApi {
         UserApi::new(self.realm_key_id.clone(), self.api_url.clone())
     }
 
-    pub fn is_authorized(&self, login: &Login) -> bool {
-        self.authorized_users.iter().filter_map(|u| u.as_str()).any(|u| {
-            u == login.user_id.as_slice()
+    /// Whether `login` may log in to this account in the circumstances of
+    /// `attempt`.  The login must be allowed by an entry in `authorized_users`,
+    /// mapped to the account in the central mapping, or belong to a member of
+    /// one of `authorized_groups`.  Otherwise the denial says why the
+    /// `authorized_users` entries did not allow it.
+    pub fn is_authorized(&self, login: &Login, attempt: Attempt) -> Result<(), Denial> {
+        let user_id = login.user_id.as_slice();
+        authorized::check(&self.authorized_users, user_id, attempt)
+        .or_else(|denial| {
+            if self.user_map.allows(user_id, &self.account.name, attempt.service) ||
+               self.authorized_by_group(user_id, attempt) {
+                Ok(())
+            }
+            else {
+                Err(denial)
+            }
+        })
+    }
+
+    // Group members are looked up on each attempt, so that membership changes
+    // apply right away.
+    fn authorized_by_group(&self, user_id: &str, attempt: Attempt) -> bool {
+        self.authorized_groups.iter()
+        .filter_map(|group| passwd::group_members(group))
+        .flat_map(|members| members.into_iter())
+        .any(|member| self.member_authorizes(&member, user_id, attempt))
+    }
+
+    // Whether the member's own configuration authorizes `user_id` for the
+    // member's own account.  Only the member's `authorized_users` is
+    // consulted, not their `authorized_groups`, and a member whose per-user
+    // file cannot be read or fails `strict_modes` is skipped.  The restrictions
+    // of the member's entries apply as they would to the member.
+    fn member_authorizes(&self, member: &str, user_id: &str, attempt: Attempt) -> bool {
+        if self.user_map.allows(user_id, member, attempt.service) {
+            return true
+        }
+        passwd::lookup(member)
+        .and_then(|account| {
+            get_user_auth_path(member, &account.home, self.use_home).ok()
+            .and_then(|auth| {
+                if self.strict_modes && check_permissions(&auth, &account).is_err() {
+                    None
+                }
+                else {
+                    read_config(&auth).ok()
+                }
+            })
         })
+        .and_then(|file| file.authorized_users)
+        .map(|users| authorized::check(&users, user_id, attempt).is_ok())
+        .unwrap_or(false)
     }
 }
 
-#[derive(PartialEq, Debug)]
+/// The module arguments and the system-wide file, which the user cannot
+/// change.  They are read once for each call into the module.  Settings that
+/// decide whether Tozny is used at all, and how decisions are recorded, only
+/// come from here.
+pub struct Trusted {
+    args:   Settings,
+    system: ConfigFile,
+    policy: Result<Vec<Rule>, ConfigError>,
+}
+
+impl Trusted {
+    /// Parses the module arguments and reads the system-wide file, which need
+    /// not exist.  Invalid `[[policy]]` rules are reported by `policy`, so that
+    /// they do not keep the rest of the configuration from being read.
+    pub fn read(args: &[String]) -> Result<Trusted, ConfigError> {
+        let opts = try!(program_opts().parse(args).map_err(ConfigError::GetoptsError));
+        let (arg_settings, system_path) = try!(parse_args(&opts));
+        if !system_path.exists() {
+            return Ok(Trusted {
+                args:   arg_settings,
+                system: ConfigFile::empty(),
+                policy: Ok(Vec::new()),
+            })
+        }
+        // The file can exempt users from Tozny, so it must be root's alone.
+        try!(check_root_owned(&system_path));
+        let table = try!(read_table(&system_path));
+        let system = try!(ConfigFile::from_table(&table));
+        let policy = get_opt(&table, "policy", policy::as_rules)
+            .map(|rules| rules.unwrap_or(Vec::new()));
+        Ok(Trusted {
+            args:   arg_settings,
+            system: system,
+            policy: policy,
+        })
+    }
+
+    /// The `[[policy]]` rules.  They decide whether the user needs Tozny at
+    /// all, so only the system-wide file may give them.
+    pub fn policy(&self) -> Result<&[Rule], &ConfigError> {
+        match self.policy {
+            Ok(ref rules) => Ok(&rules[..]),
+            Err(ref e)    => Err(e),
+        }
+    }
+
+    /// The audit log is written as root, so its location must not come from a
+    /// file that the user controls.
+    pub fn audit_log(&self) -> Option<Path> {
+        self.setting(|s| s.audit_log.clone()).map(|p| Path::new(p))
+    }
+
+    /// A user must not be able to let their own account fail open.
+    pub fn on_unavailable(&self) -> Option<OnUnavailable> {
+        self.setting(|s| s.on_unavailable)
+    }
+
+    /// Whether to authenticate the user who invoked sudo or su, rather than
+    /// the account they are becoming.  This decides whose configuration is
+    /// read, so the user must not be able to change it.
+    pub fn invoking_user(&self) -> bool {
+        self.setting(|s| s.invoking_user).unwrap_or(false)
+    }
+
+    // Module arguments take precedence over the system-wide file.
+    fn setting<T, F>(&self, f: F) -> Option<T> where F: Fn(&Settings) -> Option<T> {
+        f(&self.args).or_else(|| f(&self.system.settings))
+    }
+}
+
+// Settings read from a single configuration file.  Every setting is optional at
+// this level, because settings from the system-wide file and from the per-user
+// file are merged.
+#[derive(PartialEq, Debug, Clone)]
 struct ConfigFile {
-    realm_key_id:     KeyId,
-    api_url:          url::Url,
-    authorized_users: toml::Array,
+    realm_key_id:      Option<KeyId>,
+    api_url:           Option<url::Url>,
+    authorized_users:  Option<Vec<AuthorizedUser>>,
+    authorized_groups: Option<Vec<String>>,
+    settings:          Settings,
 }
 
 impl ConfigFile {
+    fn empty() -> ConfigFile {
+        ConfigFile {
+            realm_key_id:      None,
+            api_url:           None,
+            authorized_users:  None,
+            authorized_groups: None,
+            settings:          Settings::empty(),
+        }
+    }
+
     fn from_table(table: &toml::Table) -> Result<ConfigFile, ConfigError> {
-        let url_raw: &str = get(table, "api_url").and_then(as_str)
-            .unwrap_or("https://api.tozny.com");
-        url::Url::parse(url_raw).map_err(ConfigError::InvalidUrl)
-        .and_then(|url| {
-            get(table, "realm_key_id")
-            .and_then(as_str)
-            .and_then(move |key| {
-                get(table, "authorized_users")
-                .and_then(as_slice)
-                .map(|users| {
-                    ConfigFile {
-                        realm_key_id:     KeyId::from_slice(key),
-                        api_url:          url,
-                        authorized_users: users.to_vec(),
-                    }
-                })
-            })
+        // A misspelled key would otherwise be ignored, just as a misspelled
+        // module argument would not be.
+        for key in table.keys() {
+            if !FILE_KEYS.contains(&key.as_slice()) && !SETTINGS_KEYS.contains(&key.as_slice()) {
+                return Err(ConfigError::UnknownOption(key.clone()))
+            }
+        }
+        let realm_key_id = try!(get_opt(table, "realm_key_id", as_str));
+        let api_url      = try!(get_opt(table, "api_url", as_str));
+        let users        = try!(get_opt(table, "authorized_users",
+                                        authorized::as_authorized_users));
+        let groups       = try!(get_opt(table, "authorized_groups", as_names));
+        let settings     = try!(Settings::from_table(table));
+        let url = try!(match api_url {
+            Some(u) => url::Url::parse(u).map(Some).map_err(ConfigError::InvalidUrl),
+            None    => Ok(None),
+        });
+        Ok(ConfigFile {
+            realm_key_id:      realm_key_id.map(KeyId::from_slice),
+            api_url:           url,
+            authorized_users:  users,
+            authorized_groups: groups,
+            settings:          settings,
         })
     }
+
+    // Settings in `self` take precedence over settings in `fallback`.
+    fn merge(self, fallback: ConfigFile) -> ConfigFile {
+        ConfigFile {
+            realm_key_id:      self.realm_key_id.or(fallback.realm_key_id),
+            api_url:           self.api_url.or(fallback.api_url),
+            authorized_users:  self.authorized_users.or(fallback.authorized_users),
+            authorized_groups: self.authorized_groups.or(fallback.authorized_groups),
+            settings:          self.settings.merge(fallback.settings),
+        }
+    }
+}
+
+// Keys that only a configuration file may have, besides the settings.  The
+// `policy` rules are only read from the system-wide file.
+const FILE_KEYS: &'static [&'static str] = &[
+    "api_url", "authorized_groups", "authorized_users", "policy", "realm_key_id",
+];
+
+// Options that may be given either as module arguments (`timeout=120`) or in
+// a configuration file (`timeout = 120`).
+#[derive(PartialEq, Debug, Clone)]
+struct Settings {
+    allowed_targets:  Option<Vec<String>>,
+    audit_log:        Option<String>,
+    debug:            Option<bool>,
+    home_config:      Option<bool>,
+    invoking_user:    Option<bool>,
+    mobile_url:       Option<bool>,
+    on_unavailable:   Option<OnUnavailable>,
+    poll_interval:    Option<i64>,
+    presence:         Option<bool>,
+    presence_as_user: Option<bool>,
+    prompt:           Option<bool>,
+    push_grace:       Option<i64>,
+    qr_style:         Option<QrStyle>,
+    strict_modes:     Option<bool>,
+    timeout:          Option<i64>,
+    users_file:       Option<String>,
+}
+
+const SETTINGS_KEYS: &'static [&'static str] = &[
+    "allowed_targets", "audit_log", "debug", "home_config", "invoking_user", "mobile_url",
+    "on_unavailable", "poll_interval", "presence", "presence_as_user", "prompt", "push_grace",
+    "qr_style", "strict_modes", "timeout", "users_file",
+];
+
+impl Settings {
+    fn empty() -> Settings {
+        Settings {
+            allowed_targets:  None,
+            audit_log:        None,
+            debug:            None,
+            home_config:      None,
+            invoking_user:    None,
+            mobile_url:       None,
+            on_unavailable:   None,
+            poll_interval:    None,
+            presence:         None,
+            presence_as_user: None,
+            prompt:           None,
+            push_grace:       None,
+            qr_style:         None,
+            strict_modes:     None,
+            timeout:          None,
+            users_file:       None,
+        }
+    }
+
+    fn from_table(table: &toml::Table) -> Result<Settings, ConfigError> {
+        let mut settings = Settings::empty();
+        for key in SETTINGS_KEYS.iter() {
+            if let Some(value) = table.get(*key) {
+                try!(settings.set(key, value));
+            }
+        }
+        Ok(settings)
+    }
+
+    fn set(&mut self, key: &str, value: &toml::Value) -> Result<(), ConfigError> {
+        match key {
+            "allowed_targets"  => self.allowed_targets  = Some(try!(as_names(value))),
+            "audit_log"        => self.audit_log        = Some(try!(as_str(value)).to_string()),
+            "debug"            => self.debug            = Some(try!(as_bool(value))),
+            "home_config"      => self.home_config      = Some(try!(as_bool(value))),
+            "invoking_user"    => self.invoking_user    = Some(try!(as_bool(value))),
+            "mobile_url"       => self.mobile_url       = Some(try!(as_bool(value))),
+            "poll_interval"    => self.poll_interval    = Some(try!(as_seconds(key, value))),
+            "presence"         => self.presence         = Some(try!(as_bool(value))),
+            "presence_as_user" => self.presence_as_user = Some(try!(as_bool(value))),
+            "prompt"           => self.prompt           = Some(try!(as_bool(value))),
+            "push_grace"       => self.push_grace       = Some(try!(as_seconds(key, value))),
+            "strict_modes"     => self.strict_modes     = Some(try!(as_bool(value))),
+            "timeout"          => self.timeout          = Some(try!(as_seconds(key, value))),
+            "users_file"       => self.users_file       = Some(try!(as_str(value)).to_string()),
+            "qr_style"         => {
+                let name = try!(as_str(value));
+                self.qr_style = Some(try!(QrStyle::from_name(name).ok_or_else(|| {
+                    ConfigError::InvalidValue(key.to_string(), name.to_string())
+                })));
+            }
+            "on_unavailable"   => {
+                let name = try!(as_str(value));
+                self.on_unavailable = Some(try!(OnUnavailable::from_name(name).ok_or_else(|| {
+                    ConfigError::InvalidValue(key.to_string(), name.to_string())
+                })));
+            }
+            _ => return Err(ConfigError::UnknownOption(key.to_string())),
+        }
+        Ok(())
+    }
+
+    // Settings in `self` take precedence over settings in `fallback`.
+    fn merge(self, fallback: Settings) -> Settings {
+        Settings {
+            allowed_targets:  self.allowed_targets.or(fallback.allowed_targets),
+            audit_log:        self.audit_log.or(fallback.audit_log),
+            debug:            self.debug.or(fallback.debug),
+            home_config:      self.home_config.or(fallback.home_config),
+            invoking_user:    self.invoking_user.or(fallback.invoking_user),
+            mobile_url:       self.mobile_url.or(fallback.mobile_url),
+            on_unavailable:   self.on_unavailable.or(fallback.on_unavailable),
+            poll_interval:    self.poll_interval.or(fallback.poll_interval),
+            presence:         self.presence.or(fallback.presence),
+            presence_as_user: self.presence_as_user.or(fallback.presence_as_user),
+            prompt:           self.prompt.or(fallback.prompt),
+            push_grace:       self.push_grace.or(fallback.push_grace),
+            qr_style:         self.qr_style.or(fallback.qr_style),
+            strict_modes:     self.strict_modes.or(fallback.strict_modes),
+            timeout:          self.timeout.or(fallback.timeout),
+            users_file:       self.users_file.or(fallback.users_file),
+        }
+    }
 }
 
 fn program_opts() -> Options {
     let mut opts = Options::new();
+    opts.optflag("H", "no-home-config", "ignores authorized.toml in the user's home directory");
     opts.optflag("p", "prompt", "prompts user to press Enter (might be required with OpenSSH)");
     opts.optflag("Q", "no-qr", "suppresses display of QR code");
     opts.optflag("P", "no-presence", "disables push notifications");
@@ -103,7 +571,54 @@ fn program_opts() -> Options {
     opts
 }
 
+// Flags are shorthands for settings.  Other settings are given as free
+// arguments of the form `key=value`, or as a bare key (such as `debug`) to turn
+// a setting on.  The `config` argument, which names the system-wide
+// configuration file, can only be given as a module argument.
+fn parse_args(opts: &getopts::Matches) -> Result<(Settings, Path), ConfigError> {
+    let mut settings = Settings::empty();
+    let mut system_path = Path::new(SYSTEM_CONFIG);
+    if opts.opt_present("no-home-config") { settings.home_config = Some(false) }
+    if opts.opt_present("prompt")         { settings.prompt      = Some(true) }
+    if opts.opt_present("no-qr")          { settings.qr_style    = Some(QrStyle::Url) }
+    if opts.opt_present("no-presence")    { settings.presence    = Some(false) }
+    if opts.opt_present("no-mobile")      { settings.mobile_url  = Some(false) }
+    for arg in opts.free.iter() {
+        match arg.find('=') {
+            Some(i) => {
+                let (key, value) = (&arg[..i], &arg[i + 1..]);
+                if key == "config" {
+                    system_path = Path::new(value);
+                }
+                else {
+                    try!(settings.set(key, &arg_value(value)));
+                }
+            }
+            None => try!(settings.set(arg, &toml::Value::Boolean(true))),
+        }
+    }
+    Ok((settings, system_path))
+}
+
+// Module arguments are untyped, so guess the type that the setting expects.
+fn arg_value(value: &str) -> toml::Value {
+    match value {
+        "true"  | "yes" | "on"  => toml::Value::Boolean(true),
+        "false" | "no"  | "off" => toml::Value::Boolean(false),
+        _ => match value.parse::<i64>() {
+            Ok(n)  => toml::Value::Integer(n),
+            Err(_) => toml::Value::String(value.to_string()),
+        },
+    }
+}
+
 fn read_config(path: &Path) -> Result<ConfigFile, ConfigError> {
+    read_table(path).and_then(|table| {
+        ConfigFile::from_table(&table)
+    })
+}
+
+fn read_table(path: &Path) -> Result<toml::Table, ConfigError> {
     File::open(path)
     .read_to_string()
     .map_err(ConfigError::ErrorReading)
@@ -111,21 +626,23 @@ fn read_config(path: &Path) -> Result<ConfigFile, ConfigError> {
         toml::Parser::new(&input).parse()
             .ok_or(ConfigError::ParseError)
     })
-    .and_then(|table| {
-        ConfigFile::from_table(&table)
-    })
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum ConfigError {
     ErrorReading(std::old_io::IoError),
     GetoptsError(getopts::Fail),
+    InsecurePermissions(Path, u32),
     InvalidUrl(url::ParseError),
+    InvalidUserName(String),
+    InvalidValue(String, String),
     MissingField(String),
     MissingAuthFile(Path),
     NoHomeDir,
+    NoSuchUser(String),
     ParseError,
     TypeError(&'static str, &'static str),
+    UnknownOption(String),
 }
 
 impl fmt::Display for ConfigError {
@@ -137,9 +654,19 @@ impl fmt::Display for ConfigError {
             &ConfigError::GetoptsError(ref e) => {
                 f.write_fmt(format_args!("{}", e))
             }
+            &ConfigError::InsecurePermissions(ref path, mode) => {
+                f.write_fmt(format_args!(
+                        "Bad ownership or modes for {}: mode {:o}", path.display(), mode))
+            }
             &ConfigError::InvalidUrl(ref e) => {
                 f.write_fmt(format_args!("Invalid api_url: {}", e))
             }
+            &ConfigError::InvalidUserName(ref user) => {
+                f.write_fmt(format_args!("Invalid user name: {:?}", user))
+            }
+            &ConfigError::InvalidValue(ref key, ref value) => {
+                f.write_fmt(format_args!("Invalid value for {}: {}", key, value))
+            }
             &ConfigError::MissingField(ref key) => {
                 f.write_fmt(format_args!("Missing key in configuration: {}", key))
             }
@@ -147,7 +674,10 @@ impl fmt::Display for ConfigError {
                 f.write_fmt(format_args!("No such file, {:?}", path))
             }
             &ConfigError::NoHomeDir => {
-                f.write_str("Expected to find authorized file in home directory, but user has no home directory.")
+                f.write_str("No authorized file in /etc/tozny/authorized.d, and user has no home directory to look in.")
+            }
+            &ConfigError::NoSuchUser(ref user) => {
+                f.write_fmt(format_args!("No such user: {}", user))
             }
             &ConfigError::ParseError => {
                 // TODO: Read errors from parser object.
@@ -158,28 +688,74 @@ impl fmt::Display for ConfigError {
                         "Type error in configuration: expected {}, but got {}",
                         expected, actual))
             }
+            &ConfigError::UnknownOption(ref key) => {
+                f.write_fmt(format_args!("Unknown option: {}", key))
+            }
         }
     }
 }
 
-fn get_home(user: &str) -> Option<Path> {
-    Command::new("getent").arg("passwd").arg(user).output().ok()
-    .and_then(|out| {
-        if out.status.success() {
-            Some(out.output)
+// A file for the user in `/etc/tozny/authorized.d/` takes precedence over the
+// file in the user's home directory.  Only one of the two files is read.
+fn get_user_auth_path(user: &str, home: &Path, use_home: bool) -> Result<Path, ConfigError> {
+    // The user name comes from the client, and is used to build a path.
+    if user.is_empty() || user.starts_with(".") || user.contains("/") {
+        return Err(ConfigError::InvalidUserName(user.to_string()))
+    }
+    let mut system_auth = Path::new(AUTHORIZED_DIR);
+    system_auth.push(format!("{}.toml", user));
+    if system_auth.is_file() {
+        return Ok(system_auth)
+    }
+    if !use_home {
+        return Err(ConfigError::MissingAuthFile(system_auth))
+    }
+    // Only the file in the home directory needs one.
+    if !home.exists() {
+        return Err(ConfigError::NoHomeDir)
+    }
+    let auth = get_auth_path(home);
+    if auth.is_file() { Ok(auth) } else { Err(ConfigError::MissingAuthFile(auth)) }
+}
+
+// Modelled on OpenSSH's StrictModes: the file, and every directory above it up
+// to the user's home directory, must be owned by the user or by root, and must
+// not be writable by group or others.  Otherwise someone else could substitute
+// their own list of authorized users.
+//
+// Like sshd, the path is resolved first, so that the directories checked are
+// the ones the file is really in, not the ones a symlink was found in.
+fn check_permissions(path: &Path, account: &Passwd) -> Result<(), ConfigError> {
+    let mut current = try!(fd::canonicalize(path).map_err(|_| {
+        ConfigError::ErrorReading(IoError::last_error())
+    }));
+    let home = fd::canonicalize(&account.home).unwrap_or(account.home.clone());
+    loop {
+        let stat = try!(fs::stat(&current).map_err(ConfigError::ErrorReading));
+        let mode = stat.perm.bits();
+        if !fd::owned_by(stat.unstable.uid as uid_t, mode as mode_t, &[account.uid, 0]) {
+            return Err(ConfigError::InsecurePermissions(current, mode))
         }
-        else {
-            None
+        if current == home || !current.pop() {
+            return Ok(())
         }
-    })
-    .and_then(|bytes| {
-        String::from_utf8(bytes).ok()
-    })
-    .and_then(|passwd| {
-        let home_dir = passwd.split(":").skip(5).next().unwrap();
-        let path = Path::new(home_dir);
-        if path.exists() { Some(path) } else { None }
-    })
+    }
+}
+
+/// Checks that a file that applies to every account is owned by root and not
+/// writable by anyone else, whatever `strict_modes` says about per-user files.
+/// An application that runs as an ordinary user, such as a screen locker, may
+/// also use files of its own user, which cannot give it more than it has.
+pub fn check_root_owned(path: &Path) -> Result<(), ConfigError> {
+    let stat = try!(fs::stat(path).map_err(ConfigError::ErrorReading));
+    let mode = stat.perm.bits();
+    let euid = unsafe { geteuid() };
+    if fd::owned_by(stat.unstable.uid as uid_t, mode as mode_t, &[0, euid]) {
+        Ok(())
+    }
+    else {
+        Err(ConfigError::InsecurePermissions(path.clone(), mode))
+    }
 }
 
 fn get_auth_path(home: &Path) -> Path {
@@ -190,20 +766,349 @@ fn get_auth_path(home: &Path) -> Path {
     auth
 }
 
-fn as_str(v: &toml::Value) -> Result<&str, ConfigError> {
+pub fn as_str(v: &toml::Value) -> Result<&str, ConfigError> {
     v.as_str().ok_or_else(|| {
         ConfigError::TypeError("String", v.type_str())
     })
 }
 
-fn as_slice(v: &toml::Value) -> Result<&[toml::Value], ConfigError> {
+fn as_bool(v: &toml::Value) -> Result<bool, ConfigError> {
+    v.as_bool().ok_or_else(|| {
+        ConfigError::TypeError("Boolean", v.type_str())
+    })
+}
+
+// Durations are given as a positive number of seconds.
+fn as_seconds(key: &str, v: &toml::Value) -> Result<i64, ConfigError> {
+    v.as_integer().ok_or_else(|| {
+        ConfigError::TypeError("Integer", v.type_str())
+    })
+    .and_then(|n| {
+        if n > 0 && n <= MAX_SECONDS { Ok(n) }
+        else { Err(ConfigError::InvalidValue(key.to_string(), n.to_string())) }
+    })
+}
+
+// A list of names is an array of strings, or in a module argument, a string of
+// comma-separated names.
+pub fn as_names(v: &toml::Value) -> Result<Vec<String>, ConfigError> {
+    match v {
+        &toml::Value::String(ref names) => {
+            Ok(names.split(',').map(|n| n.trim().to_string()).filter(|n| !n.is_empty()).collect())
+        }
+        _ => {
+            let values = try!(as_slice(v));
+            values.iter().map(|n| as_str(n).map(|n| n.to_string())).collect()
+        }
+    }
+}
+
+// Address ranges in CIDR notation, given like a list of names.
+pub fn as_ranges(v: &toml::Value) -> Result<Vec<Cidr>, ConfigError> {
+    let names = try!(as_names(v));
+    names.iter().map(|n| {
+        Cidr::parse(n).ok_or_else(|| ConfigError::InvalidValue("from".to_string(), n.clone()))
+    })
+    .collect()
+}
+
+pub fn as_slice(v: &toml::Value) -> Result<&[toml::Value], ConfigError> {
     v.as_slice().ok_or_else(|| {
         ConfigError::TypeError("Array", v.type_str())
     })
 }
 
-fn get<'a>(table: &'a toml::Table, key: &str) -> Result<&'a toml::Value, ConfigError> {
+pub fn get<'a>(table: &'a toml::Table, key: &str) -> Result<&'a toml::Value, ConfigError> {
     table.get(key).ok_or_else(|| {
         ConfigError::MissingField(key.to_string())
     })
 }
+
+// A condition with a list holds if `value` matches an item of the list, and
+// does not hold if the value is unknown.  A condition without a list always
+// holds.
+pub fn matches<T, F>(allowed: &Option<Vec<T>>, value: Option<&str>, f: F) -> bool
+        where F: Fn(&str, &T) -> bool {
+    match (allowed, value) {
+        (&None, _)                  => true,
+        (&Some(ref list), Some(v))  => list.iter().any(|a| f(v, a)),
+        (&Some(_), None)            => false,
+    }
+}
+
+pub fn get_opt<'a, T, F>(table: &'a toml::Table, key: &str, f: F) -> Result<Option<T>, ConfigError>
+        where F: Fn(&'a toml::Value) -> Result<T, ConfigError> {
+    match table.get(key) {
+        Some(v) => f(v).map(Some),
+        None    => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libc::{getgid, getuid};
+    use std::old_io::{fs, File, FilePermission, TempDir, Writer};
+    use std::old_path::{GenericPath};
+    use std::old_path::posix::{Path};
+
+    use toml;
+
+    use authorized::{Attempt};
+    use passwd::{Passwd};
+    use user_map::{UserMap};
+    use super::{check_permissions, parse_args, program_opts, Config, ConfigError, ConfigFile,
+                OnUnavailable, QrStyle, Settings};
+
+    // Members that have no account, so that only the central mapping can
+    // authorize them.
+    const USERS: &'static str = r#"
+        [[user]]
+        id       = "sid_alice"
+        accounts = ["toznytest-alice"]
+
+        [[user]]
+        id       = "sid_bob"
+        accounts = ["toznytest-bob"]
+        services = ["sshd"]
+    "#;
+
+    fn config() -> Config {
+        let account = Passwd {
+            name:   "toznytest-ops".to_string(),
+            uid:    1000,
+            gid:    1000,
+            home:   Path::new("/nonexistent"),
+            shell:  Path::new("/bin/sh"),
+            groups: vec![1000],
+        };
+        let mut config = Config::for_test("sid_realm", &[], account);
+        config.user_map = UserMap::for_test(USERS);
+        config
+    }
+
+    fn attempt(service: &str) -> Attempt {
+        Attempt { service: Some(service), rhost: None }
+    }
+
+    #[test]
+    fn limits_targets_to_allowed_accounts() {
+        let mut config = config();
+        for &(ref allowed, target, may) in [
+            (None,                     "toznytest-ops", true),
+            (None,                     "root",          false),
+            (Some(vec!["deploy"]),     "deploy",        true),
+            (Some(vec!["deploy"]),     "root",          false),
+            (Some(vec!["*"]),          "root",          true),
+            (Some(vec![]),             "toznytest-ops", false),
+        ].iter() {
+            config.allowed_targets = allowed.as_ref().map(|names| {
+                names.iter().map(|n| n.to_string()).collect()
+            });
+            assert!(config.may_become(target) == may, "{:?} becoming {}", allowed, target);
+        }
+    }
+
+    #[test]
+    fn members_authorize_through_the_central_mapping() {
+        let config = config();
+        for &(member, user_id, service, authorized) in [
+            ("toznytest-alice", "sid_alice", "sshd", true),
+            ("toznytest-alice", "sid_alice", "sudo", true),
+            ("toznytest-alice", "sid_bob",   "sshd", false),
+            ("toznytest-bob",   "sid_bob",   "sshd", true),
+            ("toznytest-bob",   "sid_bob",   "sudo", false),
+            ("toznytest-carol", "sid_alice", "sshd", false),
+        ].iter() {
+            assert!(config.member_authorizes(member, user_id, attempt(service)) == authorized,
+                    "{} for {} through {}", user_id, member, service);
+        }
+    }
+
+    #[test]
+    fn unknown_groups_authorize_no_one() {
+        let mut config = config();
+        assert!(!config.authorized_by_group("sid_alice", attempt("sshd")));
+        config.authorized_groups = vec!["toznytest-no-such-group".to_string()];
+        assert!(!config.authorized_by_group("sid_alice", attempt("sshd")));
+    }
+
+    // An account of the user running the tests, with its home directory in
+    // `home`.
+    fn account_in(home: &Path) -> Passwd {
+        Passwd {
+            name:   "toznytest-ops".to_string(),
+            uid:    unsafe { getuid() },
+            gid:    unsafe { getgid() },
+            home:   home.clone(),
+            shell:  Path::new("/bin/sh"),
+            groups: Vec::new(),
+        }
+    }
+
+    fn temp_dir() -> TempDir {
+        TempDir::new("toznyauth_pam").ok().expect("cannot create directory")
+    }
+
+    // The mode is set after creating, so that the umask does not apply.
+    fn write_file(path: &Path, mode: u32) {
+        File::create(path).write_str("authorized_users = []\n").ok().expect("cannot write file");
+        fs::chmod(path, FilePermission::from_bits_truncate(mode)).ok().expect("cannot chmod");
+    }
+
+    fn make_dir(path: &Path, mode: u32) {
+        fs::mkdir(path, FilePermission::from_bits_truncate(0o700)).ok().expect("cannot mkdir");
+        fs::chmod(path, FilePermission::from_bits_truncate(mode)).ok().expect("cannot chmod");
+    }
+
+    // Returns the name of the file or directory that was rejected.
+    fn rejected(path: &Path, account: &Passwd) -> String {
+        match check_permissions(path, account) {
+            Err(ConfigError::InsecurePermissions(ref p, _)) => {
+                p.filename_str().unwrap_or("").to_string()
+            }
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(()) => panic!("accepted {}", path.display()),
+        }
+    }
+
+    #[test]
+    fn accepts_private_files() {
+        let home = temp_dir();
+        let file = home.path().join("authorized.toml");
+        write_file(&file, 0o600);
+        assert!(check_permissions(&file, &account_in(home.path())).is_ok());
+    }
+
+    #[test]
+    fn rejects_group_writable_files() {
+        let home = temp_dir();
+        let file = home.path().join("authorized.toml");
+        write_file(&file, 0o620);
+        assert_eq!(rejected(&file, &account_in(home.path())), "authorized.toml");
+    }
+
+    #[test]
+    fn rejects_group_writable_directories() {
+        let home = temp_dir();
+        let dir = home.path().join("tozny");
+        make_dir(&dir, 0o770);
+        let file = dir.join("authorized.toml");
+        write_file(&file, 0o600);
+        assert_eq!(rejected(&file, &account_in(home.path())), "tozny");
+    }
+
+    #[test]
+    fn rejects_files_owned_by_others() {
+        let home = temp_dir();
+        let file = home.path().join("authorized.toml");
+        write_file(&file, 0o600);
+        let mut account = account_in(home.path());
+        if account.uid == 0 {
+            // Files owned by root are always allowed, so give this one away.
+            fs::chown(&file, 4321, -1).ok().expect("cannot chown");
+            account.uid = 1234;
+        }
+        else {
+            account.uid += 1;
+        }
+        assert_eq!(rejected(&file, &account), "authorized.toml");
+    }
+
+    #[test]
+    fn checks_where_symlinks_lead() {
+        let tmp = temp_dir();
+        let home = tmp.path().join("home");
+        make_dir(&home, 0o700);
+        let shared = tmp.path().join("shared");
+        make_dir(&shared, 0o770);
+        let target = shared.join("authorized.toml");
+        write_file(&target, 0o600);
+        let link = home.join("authorized.toml");
+        fs::symlink(&target, &link).ok().expect("cannot create symlink");
+        assert_eq!(rejected(&link, &account_in(&home)), "shared");
+    }
+
+    fn args(args: &[&str]) -> Result<(Settings, Path), ConfigError> {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        let opts = try!(program_opts().parse(&args[..]).map_err(ConfigError::GetoptsError));
+        parse_args(&opts)
+    }
+
+    fn file(input: &str) -> Result<ConfigFile, ConfigError> {
+        let table = toml::Parser::new(input).parse().expect("invalid TOML");
+        ConfigFile::from_table(&table)
+    }
+
+    #[test]
+    fn parses_module_arguments() {
+        let (settings, path) = args(&[
+            "timeout=120", "debug", "qr_style=ascii", "on_unavailable=ignore",
+            "presence=no", "--no-home-config", "-p", "config=/etc/tozny/sshd.toml",
+        ]).ok().expect("rejected arguments");
+        assert_eq!(settings.timeout, Some(120));
+        assert_eq!(settings.debug, Some(true));
+        assert_eq!(settings.qr_style, Some(QrStyle::Ascii));
+        assert_eq!(settings.on_unavailable, Some(OnUnavailable::Ignore));
+        assert_eq!(settings.presence, Some(false));
+        assert_eq!(settings.home_config, Some(false));
+        assert_eq!(settings.prompt, Some(true));
+        assert_eq!(settings.poll_interval, None);
+        assert!(path == Path::new("/etc/tozny/sshd.toml"));
+    }
+
+    #[test]
+    fn rejects_invalid_module_arguments() {
+        for &(arg, expected) in [
+            ("no-home-config",        "UnknownOption"),
+            ("home-config=false",     "UnknownOption"),
+            ("timeout=soon",          "TypeError"),
+            ("debug=maybe",           "TypeError"),
+            ("qr_style=sixel",        "InvalidValue"),
+            ("on_unavailable=allow",  "InvalidValue"),
+            ("--no-such-flag",        "GetoptsError"),
+        ].iter() {
+            let error = match args(&[arg]) {
+                Err(ConfigError::UnknownOption(_)) => "UnknownOption",
+                Err(ConfigError::TypeError(..))    => "TypeError",
+                Err(ConfigError::InvalidValue(..)) => "InvalidValue",
+                Err(ConfigError::GetoptsError(_))  => "GetoptsError",
+                Err(e)                             => panic!("{}: unexpected error: {}", arg, e),
+                Ok(_)                              => panic!("accepted {}", arg),
+            };
+            assert!(error == expected, "{} gave {}", arg, error);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_keys_in_files() {
+        match file("timout = 120\n") {
+            Err(ConfigError::UnknownOption(ref key)) => assert_eq!(key.as_slice(), "timout"),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_)  => panic!("accepted a misspelled key"),
+        }
+        assert!(file("timeout = 120\npolicy = []\n").is_ok());
+    }
+
+    // Merges settings the same way that `Config::build` does.
+    #[test]
+    fn arguments_win_over_user_file_over_system_file() {
+        for &(arg, user, system, expected) in [
+            (Some(10), Some(20), Some(30), Some(10)),
+            (Some(10), None,     Some(30), Some(10)),
+            (None,     Some(20), Some(30), Some(20)),
+            (None,     None,     Some(30), Some(30)),
+            (None,     None,     None,     None),
+        ].iter() {
+            let arg_list: Vec<String> = arg.iter().map(|t| format!("timeout={}", t)).collect();
+            let arg_refs: Vec<&str> = arg_list.iter().map(|a| a.as_slice()).collect();
+            let (arg_settings, _) = args(&arg_refs).ok().expect("rejected arguments");
+            let to_file = |timeout: Option<i64>| {
+                let input = timeout.map(|t| format!("timeout = {}\n", t)).unwrap_or(String::new());
+                file(&input).ok().expect("rejected file")
+            };
+            let settings = arg_settings.merge(to_file(user).merge(to_file(system)).settings);
+            assert!(settings.timeout == expected,
+                    "{:?} from {:?}, {:?}, {:?}", settings.timeout, arg, user, system);
+        }
+    }
+}
@@ -0,0 +1,57 @@
+// The PAM conversation, behind a trait so that messages to the user can be
+// captured, and answers scripted, without an application on the other end.
+
+use pam::constants::*;
+use pam::conv::{PamConv};
+
+#[cfg(test)]
+use std::collections::{VecDeque};
+#[cfg(test)]
+use std::sync::{Mutex};
+
+pub trait Conversation {
+    /// Sends one message, and returns the answer to a prompt.
+    fn send(&self, style: PamMessageStyle, msg: &str) -> Result<Option<String>, PamResultCode>;
+}
+
+impl Conversation for PamConv {
+    fn send(&self, style: PamMessageStyle, msg: &str) -> Result<Option<String>, PamResultCode> {
+        PamConv::send(self, style, msg)
+    }
+}
+
+/// Records every message, and answers prompts from a script.  Prompts are sent
+/// from another thread, hence the locks.
+#[cfg(test)]
+pub struct FakeConversation {
+    answers:  Mutex<VecDeque<String>>,
+    messages: Mutex<Vec<(PamMessageStyle, String)>>,
+}
+
+#[cfg(test)]
+impl FakeConversation {
+    /// Prompts are answered with `answers` in order, and then with empty lines.
+    pub fn new(answers: &[&str]) -> FakeConversation {
+        FakeConversation {
+            answers:  Mutex::new(answers.iter().map(|a| a.to_string()).collect()),
+            messages: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn messages(&self) -> Vec<(PamMessageStyle, String)> {
+        self.messages.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl Conversation for FakeConversation {
+    fn send(&self, style: PamMessageStyle, msg: &str) -> Result<Option<String>, PamResultCode> {
+        self.messages.lock().unwrap().push((style, msg.to_string()));
+        if style == PAM_PROMPT_ECHO_OFF || style == PAM_PROMPT_ECHO_ON {
+            Ok(Some(self.answers.lock().unwrap().pop_front().unwrap_or(String::new())))
+        }
+        else {
+            Ok(None)
+        }
+    }
+}
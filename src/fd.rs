@@ -0,0 +1,206 @@
+// Thin wrappers around file descriptor system calls that the standard library
+// does not expose, such as `openat` and `fchown`.  These make it possible to
+// operate on files relative to a directory that has already been checked,
+// instead of resolving a path again for each operation.
+//
+// Errors are reported as `errno` values.
+
+use libc;
+use libc::{c_char, c_int, c_void, gid_t, mode_t, size_t, ssize_t, uid_t};
+use std::ffi::{CStr, CString};
+use std::old_path::posix::{Path};
+use std::{mem, os};
+
+pub type Errno = c_int;
+
+pub const ENOENT: Errno = 2;
+pub const EACCES: Errno = 13;
+pub const EEXIST: Errno = 17;
+
+// Linux values
+pub const O_RDONLY:    c_int = 0o0;
+pub const O_WRONLY:    c_int = 0o1;
+pub const O_CREAT:     c_int = 0o100;
+pub const O_EXCL:      c_int = 0o200;
+pub const O_APPEND:    c_int = 0o2000;
+pub const O_DIRECTORY: c_int = 0o200000;
+pub const O_NOFOLLOW:  c_int = 0o400000;
+pub const O_CLOEXEC:   c_int = 0o2000000;
+
+pub const S_IFMT:  mode_t = 0o170000;
+pub const S_IFDIR: mode_t = 0o040000;
+pub const S_IFREG: mode_t = 0o100000;
+
+/// Permission bits that would let another user tamper with a file.
+pub const GROUP_WORLD_WRITABLE: mode_t = 0o022;
+
+// Linux value
+const PATH_MAX: usize = 4096;
+
+pub const LOCK_EX: c_int = 2;
+pub const LOCK_UN: c_int = 8;
+
+extern {
+    fn openat(dirfd: c_int, pathname: *const c_char, flags: c_int, mode: mode_t) -> c_int;
+    fn mkdirat(dirfd: c_int, pathname: *const c_char, mode: mode_t) -> c_int;
+    fn renameat(olddirfd: c_int, oldpath: *const c_char,
+                newdirfd: c_int, newpath: *const c_char) -> c_int;
+    fn unlinkat(dirfd: c_int, pathname: *const c_char, flags: c_int) -> c_int;
+    fn fchown(fd: c_int, owner: uid_t, group: gid_t) -> c_int;
+    fn fchmod(fd: c_int, mode: mode_t) -> c_int;
+    fn fsync(fd: c_int) -> c_int;
+    fn flock(fd: c_int, operation: c_int) -> c_int;
+    fn setfsuid(fsuid: uid_t) -> c_int;
+    fn setfsgid(fsgid: gid_t) -> c_int;
+    fn realpath(path: *const c_char, resolved_path: *mut c_char) -> *mut c_char;
+}
+
+/// An open file descriptor, which is closed when dropped.
+pub struct Fd(c_int);
+
+impl Fd {
+    pub fn open(path: &Path, flags: c_int, mode: mode_t) -> Result<Fd, Errno> {
+        let c_path = try!(CString::new(path.as_vec()).map_err(|_| ENOENT));
+        check_fd(unsafe { libc::open(c_path.as_ptr(), flags | O_CLOEXEC, mode) })
+    }
+
+    /// Opens `name` relative to this directory.  `name` must not contain
+    /// slashes.
+    pub fn openat(&self, name: &str, flags: c_int, mode: mode_t) -> Result<Fd, Errno> {
+        let c_name = try!(to_c_name(name));
+        check_fd(unsafe { openat(self.0, c_name.as_ptr(), flags | O_CLOEXEC, mode) })
+    }
+
+    pub fn mkdirat(&self, name: &str, mode: mode_t) -> Result<(), Errno> {
+        let c_name = try!(to_c_name(name));
+        check(unsafe { mkdirat(self.0, c_name.as_ptr(), mode) })
+    }
+
+    /// Atomically replaces `to` with `from`, both in this directory.
+    pub fn renameat(&self, from: &str, to: &str) -> Result<(), Errno> {
+        let c_from = try!(to_c_name(from));
+        let c_to   = try!(to_c_name(to));
+        check(unsafe { renameat(self.0, c_from.as_ptr(), self.0, c_to.as_ptr()) })
+    }
+
+    pub fn unlinkat(&self, name: &str) -> Result<(), Errno> {
+        let c_name = try!(to_c_name(name));
+        check(unsafe { unlinkat(self.0, c_name.as_ptr(), 0) })
+    }
+
+    pub fn fstat(&self) -> Result<libc::stat, Errno> {
+        let mut st: libc::stat = unsafe { mem::zeroed() };
+        try!(check(unsafe { libc::fstat(self.0, &mut st) }));
+        Ok(st)
+    }
+
+    pub fn fchown(&self, uid: uid_t, gid: gid_t) -> Result<(), Errno> {
+        check(unsafe { fchown(self.0, uid, gid) })
+    }
+
+    pub fn fchmod(&self, mode: mode_t) -> Result<(), Errno> {
+        check(unsafe { fchmod(self.0, mode) })
+    }
+
+    pub fn fsync(&self) -> Result<(), Errno> {
+        check(unsafe { fsync(self.0) })
+    }
+
+    pub fn flock(&self, operation: c_int) -> Result<(), Errno> {
+        check(unsafe { flock(self.0, operation) })
+    }
+
+    pub fn read_to_end(&self) -> Result<Vec<u8>, Errno> {
+        let mut out = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = unsafe {
+                libc::read(self.0, buf.as_mut_ptr() as *mut c_void, buf.len() as size_t)
+            };
+            if n < 0 { return Err(errno()) }
+            if n == 0 { return Ok(out) }
+            out.push_all(&buf[..n as usize]);
+        }
+    }
+
+    pub fn write_all(&self, bytes: &[u8]) -> Result<(), Errno> {
+        let mut written = 0;
+        while written < bytes.len() {
+            let rest = &bytes[written..];
+            let n: ssize_t = unsafe {
+                libc::write(self.0, rest.as_ptr() as *const c_void, rest.len() as size_t)
+            };
+            if n < 0 { return Err(errno()) }
+            written += n as usize;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Fd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+/// Switches the filesystem user and group ids of the current thread, so that
+/// file operations are checked against the permissions of another user.  The
+/// previous ids are restored when this value is dropped.
+pub struct FsIds {
+    uid: uid_t,
+    gid: gid_t,
+}
+
+impl FsIds {
+    pub fn switch(uid: uid_t, gid: gid_t) -> FsIds {
+        // The group must be changed first, while the process still has the
+        // privileges to do so.
+        let old_gid = unsafe { setfsgid(gid) } as gid_t;
+        let old_uid = unsafe { setfsuid(uid) } as uid_t;
+        FsIds { uid: old_uid, gid: old_gid }
+    }
+}
+
+impl Drop for FsIds {
+    fn drop(&mut self) {
+        unsafe {
+            setfsuid(self.uid);
+            setfsgid(self.gid);
+        }
+    }
+}
+
+/// Whether a file with this owner and mode can only be changed by one of
+/// `owners`.
+pub fn owned_by(uid: uid_t, mode: mode_t, owners: &[uid_t]) -> bool {
+    owners.iter().any(|owner| *owner == uid) && mode & GROUP_WORLD_WRITABLE == 0
+}
+
+/// Resolves every symlink, `.` and `..` in `path`, which must exist.
+pub fn canonicalize(path: &Path) -> Result<Path, Errno> {
+    let c_path = try!(CString::new(path.as_vec()).map_err(|_| ENOENT));
+    let mut buf = [0 as c_char; PATH_MAX];
+    if unsafe { realpath(c_path.as_ptr(), buf.as_mut_ptr()) }.is_null() {
+        return Err(errno())
+    }
+    Ok(Path::new(unsafe { CStr::from_ptr(buf.as_ptr()) }.to_bytes()))
+}
+
+pub fn errno() -> Errno {
+    os::errno() as Errno
+}
+
+fn to_c_name(name: &str) -> Result<CString, Errno> {
+    if name.contains("/") {
+        return Err(ENOENT)
+    }
+    CString::new(name).map_err(|_| ENOENT)
+}
+
+fn check_fd(fd: c_int) -> Result<Fd, Errno> {
+    if fd < 0 { Err(errno()) } else { Ok(Fd(fd)) }
+}
+
+fn check(res: c_int) -> Result<(), Errno> {
+    if res < 0 { Err(errno()) } else { Ok(()) }
+}
@@ -0,0 +1,46 @@
+// Finds the person behind a sudo or su request.  The target account, such as
+// root, says nothing about who is asking to become it, so with the
+// `invoking_user` option the module authenticates this user instead.
+
+use pam::constants::*;
+use pam::module::{PamHandleT};
+
+use pam_ext;
+
+// Services that run on behalf of a user who is already logged in, and that set
+// `PAM_RUSER` to that user.  For any other service, such as sshd, there is no
+// invoking user.
+const INVOKING_SERVICES: &'static [&'static str] = &["su", "su-l", "sudo", "sudo-i"];
+
+/// Whether `service` is one that `invoking_user` applies to.
+pub fn applies_to(service: Option<&str>) -> bool {
+    service.map_or(false, |s| INVOKING_SERVICES.iter().any(|known| *known == s))
+}
+
+/// Returns the name of the user who invoked the application, as sudo and su
+/// report it in `PAM_RUSER`.
+pub fn invoking_user(pamh: &PamHandleT) -> Option<String> {
+    pam_ext::get_str_item(pamh, PAM_RUSER)
+    .and_then(|ruser| if ruser.is_empty() { None } else { Some(ruser) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{applies_to};
+
+    #[test]
+    fn applies_only_to_sudo_and_su() {
+        for &(service, applies) in [
+            (Some("sudo"),     true),
+            (Some("sudo-i"),   true),
+            (Some("su"),       true),
+            (Some("su-l"),     true),
+            (Some("sshd"),     false),
+            (Some("login"),    false),
+            (Some("sudoedit"), false),
+            (None,             false),
+        ].iter() {
+            assert!(applies_to(service) == applies, "{:?}", service);
+        }
+    }
+}
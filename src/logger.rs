@@ -0,0 +1,133 @@
+// Server-side record of authentication decisions, written with pam_syslog to
+// the authpriv facility.  Each message ends with the context of the attempt, so
+// that admins can tell who logged in, from where, and why an attempt failed.
+
+use libc::{c_int};
+use pam::constants::*;
+use pam::module::{PamHandleT};
+use std::cell::{Cell, RefCell};
+
+use pam_ext;
+
+/// How the user was asked to approve the login.
+#[derive(Clone, Copy)]
+pub enum Method {
+    Push,
+    Qr,
+}
+
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Method::Push => "push",
+            Method::Qr   => "qr",
+        }
+    }
+}
+
+pub struct Logger<'a> {
+    pamh:          Option<&'a PamHandleT>,
+    debug:         Cell<bool>,
+    pub unix_user: String,
+    pub service:   Option<String>,
+    pub ruser:     Option<String>,
+    pub rhost:     Option<String>,
+    pub tty:       Option<String>,
+    tozny_user:    RefCell<Option<String>>,
+    session_id:    RefCell<Option<String>>,
+    method:        Cell<Option<Method>>,
+}
+
+impl<'a> Logger<'a> {
+    pub fn new(pamh: &'a PamHandleT, unix_user: &str) -> Logger<'a> {
+        Logger {
+            pamh:       Some(pamh),
+            debug:      Cell::new(false),
+            unix_user:  unix_user.to_string(),
+            service:    pam_ext::get_str_item(pamh, PAM_SERVICE),
+            ruser:      pam_ext::get_str_item(pamh, PAM_RUSER),
+            rhost:      pam_ext::get_str_item(pamh, PAM_RHOST),
+            tty:        pam_ext::get_str_item(pamh, PAM_TTY),
+            tozny_user: RefCell::new(None),
+            session_id: RefCell::new(None),
+            method:     Cell::new(None),
+        }
+    }
+
+    /// A logger without a PAM handle, which discards messages.
+    #[cfg(test)]
+    pub fn discard(unix_user: &str) -> Logger<'static> {
+        Logger {
+            pamh:       None,
+            debug:      Cell::new(false),
+            unix_user:  unix_user.to_string(),
+            service:    None,
+            ruser:      None,
+            rhost:      None,
+            tty:        None,
+            tozny_user: RefCell::new(None),
+            session_id: RefCell::new(None),
+            method:     Cell::new(None),
+        }
+    }
+
+    /// Enables messages logged with `debug`.
+    pub fn set_debug(&self, debug: bool) {
+        self.debug.set(debug);
+    }
+
+    /// Includes the Tozny user id in subsequent messages.
+    pub fn set_tozny_user(&self, user_id: &str) {
+        *self.tozny_user.borrow_mut() = Some(user_id.to_string());
+    }
+
+    pub fn tozny_user(&self) -> Option<String> {
+        self.tozny_user.borrow().clone()
+    }
+
+    /// Records the Tozny login session for the audit log.
+    pub fn set_session_id(&self, session_id: &str) {
+        *self.session_id.borrow_mut() = Some(session_id.to_string());
+    }
+
+    pub fn session_id(&self) -> Option<String> {
+        self.session_id.borrow().clone()
+    }
+
+    /// Records how the user was last asked to approve the login.
+    pub fn set_method(&self, method: Method) {
+        self.method.set(Some(method));
+    }
+
+    pub fn method(&self) -> Option<Method> {
+        self.method.get()
+    }
+
+    pub fn err(&self, msg: &str)     { self.log(pam_ext::LOG_ERR, msg) }
+    pub fn warning(&self, msg: &str) { self.log(pam_ext::LOG_WARNING, msg) }
+    pub fn notice(&self, msg: &str)  { self.log(pam_ext::LOG_NOTICE, msg) }
+    pub fn info(&self, msg: &str)    { self.log(pam_ext::LOG_INFO, msg) }
+
+    /// Verbose traces, only logged with the `debug` option.
+    pub fn debug(&self, msg: &str) {
+        if self.debug.get() {
+            self.log(pam_ext::LOG_DEBUG, msg)
+        }
+    }
+
+    fn log(&self, priority: c_int, msg: &str) {
+        let tozny_user = self.tozny_user.borrow();
+        for pamh in self.pamh.iter() {
+            pam_ext::syslog(*pamh, priority, &format!(
+                "{}; user={} service={} rhost={} tozny_user={}",
+                msg, self.unix_user,
+                or_unknown(&self.service), or_unknown(&self.rhost), or_unknown(&*tozny_user)));
+        }
+    }
+}
+
+/// A PAM item as it appears in log messages and session records, where `-`
+/// stands for an item that was not set.
+pub fn or_unknown(item: &Option<String>) -> &str {
+    item.as_ref().map(|s| s.as_slice()).unwrap_or("-")
+}
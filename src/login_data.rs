@@ -0,0 +1,26 @@
+// The Tozny login verified by `pam_sm_authenticate` is stored on the PAM handle,
+// so that the account, session and setcred phases of the same transaction can
+// read the Tozny user id, display name and session id without contacting the
+// API again.
+
+use pam::constants::{PamResultCode};
+use pam::module::{PamHandleT};
+use tozny_auth::login::{Login};
+
+use pam_ext;
+
+const LOGIN_DATA: &'static str = "toznyauth_pam_login";
+
+pub fn store(pamh: &PamHandleT, login: Login) -> Result<(), PamResultCode> {
+    pam_ext::set_data(pamh, LOGIN_DATA, Some(Box::new(login)))
+}
+
+pub fn get(pamh: &PamHandleT) -> Option<&Login> {
+    pam_ext::get_data::<Login>(pamh, LOGIN_DATA)
+}
+
+/// Forgets any login from an earlier authentication attempt in this
+/// transaction, so that a failed retry cannot leave a stale identity behind.
+pub fn clear(pamh: &PamHandleT) {
+    let _ = pam_ext::set_data::<Login>(pamh, LOGIN_DATA, None);
+}
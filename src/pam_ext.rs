@@ -0,0 +1,131 @@
+// Bindings for libpam functions that are not wrapped by the `pam` crate.
+
+use libc::{c_char, c_int, c_void};
+use pam::constants::*;
+use pam::module::{PamHandleT};
+use std::ffi::{CStr, CString};
+use std::{mem, ptr};
+
+pub const LOG_ERR:     c_int = 3;
+pub const LOG_WARNING: c_int = 4;
+pub const LOG_NOTICE:  c_int = 5;
+pub const LOG_INFO:    c_int = 6;
+pub const LOG_DEBUG:   c_int = 7;
+
+#[link(name = "pam")]
+extern {
+    fn pam_get_item(pamh: *const PamHandleT, item_type: PamItemType,
+                    item: *mut *const c_void) -> PamResultCode;
+    fn pam_syslog(pamh: *const PamHandleT, priority: c_int, fmt: *const c_char, ...);
+    fn pam_set_data(pamh: *const PamHandleT, module_data_name: *const c_char,
+                    data: *mut c_void,
+                    cleanup: Option<extern fn(*const PamHandleT, *mut c_void, c_int)>
+                    ) -> PamResultCode;
+    fn pam_get_data(pamh: *const PamHandleT, module_data_name: *const c_char,
+                    data: *mut *const c_void) -> PamResultCode;
+    fn pam_putenv(pamh: *const PamHandleT, name_value: *const c_char) -> PamResultCode;
+    fn pam_getenv(pamh: *const PamHandleT, name: *const c_char) -> *const c_char;
+}
+
+/// Sets a variable in the PAM environment, which applications such as sshd and
+/// login copy into the environment of the session.
+pub fn putenv(pamh: &PamHandleT, name: &str, value: &str) -> Result<(), PamResultCode> {
+    put(pamh, &format!("{}={}", name, value))
+}
+
+/// Reads a variable from the PAM environment.  Returns `None` if it is not set.
+pub fn getenv(pamh: &PamHandleT, name: &str) -> Option<String> {
+    let c_name = match CString::new(name) {
+        Ok(n)  => n,
+        Err(_) => return None,
+    };
+    let value = unsafe { pam_getenv(pamh, c_name.as_ptr()) };
+    if value.is_null() {
+        return None
+    }
+    let bytes = unsafe { CStr::from_ptr(value) }.to_bytes();
+    String::from_utf8(bytes.to_vec()).ok()
+}
+
+/// Removes a variable from the PAM environment.
+pub fn unsetenv(pamh: &PamHandleT, name: &str) {
+    // Deleting a variable that is not set is an error, which is not
+    // interesting.
+    let _ = put(pamh, name);
+}
+
+fn put(pamh: &PamHandleT, name_value: &str) -> Result<(), PamResultCode> {
+    let c_name_value = try!(CString::new(name_value).map_err(|_| PAM_BUF_ERR));
+    let res = unsafe { pam_putenv(pamh, c_name_value.as_ptr()) };
+    if res == PAM_SUCCESS { Ok(()) } else { Err(res) }
+}
+
+/// Stores a value on the PAM handle, where it is visible to every phase of the
+/// current transaction.  Any previous value stored under the same key is
+/// dropped.  Passing `None` removes the value.
+pub fn set_data<T>(pamh: &PamHandleT, key: &str, data: Option<Box<T>>
+                   ) -> Result<(), PamResultCode> {
+    let c_key = try!(CString::new(key).map_err(|_| PAM_BUF_ERR));
+    let res = unsafe {
+        match data {
+            Some(d) => {
+                let raw: *mut T = mem::transmute(d);
+                pam_set_data(pamh, c_key.as_ptr(), raw as *mut c_void, Some(cleanup::<T>))
+            },
+            None => pam_set_data(pamh, c_key.as_ptr(), ptr::null_mut(), None),
+        }
+    };
+    if res == PAM_SUCCESS { Ok(()) } else { Err(res) }
+}
+
+/// Reads a value stored with `set_data`.  The caller is responsible for asking
+/// for the same type that was stored under `key`.
+pub fn get_data<'a, T>(pamh: &'a PamHandleT, key: &str) -> Option<&'a T> {
+    let c_key = match CString::new(key) {
+        Ok(k)  => k,
+        Err(_) => return None,
+    };
+    let mut data: *const c_void = ptr::null();
+    let res = unsafe { pam_get_data(pamh, c_key.as_ptr(), &mut data) };
+    if res == PAM_SUCCESS && !data.is_null() {
+        Some(unsafe { &*(data as *const T) })
+    }
+    else {
+        None
+    }
+}
+
+// Called by libpam when data is replaced, or when the transaction ends with
+// `pam_end`.
+extern fn cleanup<T>(_: *const PamHandleT, data: *mut c_void, _: c_int) {
+    if !data.is_null() {
+        let boxed: Box<T> = unsafe { mem::transmute(data as *mut T) };
+        mem::drop(boxed);
+    }
+}
+
+/// Reads a string-valued PAM item, such as `PAM_SERVICE`, `PAM_RHOST` or
+/// `PAM_TTY`.  Returns `None` if the item is not set.
+pub fn get_str_item(pamh: &PamHandleT, item_type: PamItemType) -> Option<String> {
+    let mut item: *const c_void = ptr::null();
+    let res = unsafe { pam_get_item(pamh, item_type, &mut item) };
+    if res != PAM_SUCCESS || item.is_null() {
+        return None
+    }
+    let bytes = unsafe { CStr::from_ptr(item as *const c_char) }.to_bytes();
+    String::from_utf8(bytes.to_vec()).ok()
+}
+
+/// Writes a message to syslog, prefixed by libpam with the module and service
+/// names.
+pub fn syslog(pamh: &PamHandleT, priority: c_int, msg: &str) {
+    // The message is passed as an argument to a constant format string, so
+    // that `%` in user-controlled input is not interpreted.
+    let fmt = b"%s\0";
+    match CString::new(msg.replace("\0", "")) {
+        Ok(c_msg) => unsafe {
+            pam_syslog(pamh, priority, fmt.as_ptr() as *const c_char, c_msg.as_ptr())
+        },
+        Err(_) => (),
+    }
+}
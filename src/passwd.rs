@@ -0,0 +1,200 @@
+// Account lookups through the C library, so that NSS sources such as LDAP are
+// consulted in the same way as by other PAM modules, without running `getent`
+// or `id` from whatever `$PATH` the host process happens to have.
+
+use libc::{c_char, c_int, gid_t, size_t, uid_t};
+use std::ffi::{CStr, CString};
+use std::old_path::posix::{Path};
+use std::ptr;
+
+// glibc returns ERANGE when the buffer is too small for the entry.
+const ERANGE: c_int = 34;
+
+// Upper bound on buffer sizes, to avoid looping forever on a broken NSS module.
+const MAX_BUFFER: usize = 1024 * 1024;
+
+#[repr(C)]
+struct passwd {
+    pw_name:   *mut c_char,
+    pw_passwd: *mut c_char,
+    pw_uid:    uid_t,
+    pw_gid:    gid_t,
+    pw_gecos:  *mut c_char,
+    pw_dir:    *mut c_char,
+    pw_shell:  *mut c_char,
+}
+
+#[repr(C)]
+struct group {
+    gr_name:   *mut c_char,
+    gr_passwd: *mut c_char,
+    gr_gid:    gid_t,
+    gr_mem:    *mut *mut c_char,
+}
+
+extern {
+    fn getgrnam_r(name: *const c_char, grp: *mut group, buf: *mut c_char, buflen: size_t,
+                  result: *mut *mut group) -> c_int;
+    fn getpwnam_r(name: *const c_char, pwd: *mut passwd, buf: *mut c_char, buflen: size_t,
+                  result: *mut *mut passwd) -> c_int;
+    fn getgrouplist(user: *const c_char, group: gid_t, groups: *mut gid_t,
+                    ngroups: *mut c_int) -> c_int;
+}
+
+/// A unix account, as seen by NSS.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Passwd {
+    pub name:   String,
+    pub uid:    uid_t,
+    pub gid:    gid_t,
+    pub home:   Path,
+    pub shell:  Path,
+    /// Supplementary groups, including the primary group
+    pub groups: Vec<gid_t>,
+}
+
+/// Looks up an account by name.  Returns `None` if there is no such account, or
+/// if the lookup fails.
+pub fn lookup(user: &str) -> Option<Passwd> {
+    let c_user = match CString::new(user) {
+        Ok(u)  => u,
+        Err(_) => return None,
+    };
+    get_pwnam(&c_user).and_then(|mut pw| {
+        get_groups(&c_user, pw.gid).map(|groups| {
+            pw.groups = groups;
+            pw
+        })
+    })
+}
+
+/// Returns the names of the members of a group, as listed in the group
+/// database.  Accounts whose primary group this is are not included, because
+/// finding them would mean enumerating every account.
+pub fn group_members(name: &str) -> Option<Vec<String>> {
+    get_grnam(name, |grp| {
+        if grp.gr_mem.is_null() {
+            return None
+        }
+        let mut members = Vec::new();
+        let mut i = 0;
+        loop {
+            let member = unsafe { *grp.gr_mem.offset(i) };
+            if member.is_null() {
+                return Some(members)
+            }
+            members.extend(unsafe { to_string(member) }.into_iter());
+            i += 1;
+        }
+    })
+}
+
+/// Looks up the id of a group by name.
+pub fn group_id(name: &str) -> Option<gid_t> {
+    get_grnam(name, |grp| Some(grp.gr_gid))
+}
+
+// Calls `getgrnam_r`, growing the buffer as needed, and passes the entry to
+// `f` while the buffer that its strings point into is still alive.
+fn get_grnam<T, F>(name: &str, f: F) -> Option<T> where F: Fn(&group) -> Option<T> {
+    let c_name = match CString::new(name) {
+        Ok(n)  => n,
+        Err(_) => return None,
+    };
+    let mut buf: Vec<c_char> = Vec::with_capacity(1024);
+    loop {
+        let mut grp = group {
+            gr_name:   ptr::null_mut(),
+            gr_passwd: ptr::null_mut(),
+            gr_gid:    0,
+            gr_mem:    ptr::null_mut(),
+        };
+        let mut result: *mut group = ptr::null_mut();
+        let cap = buf.capacity();
+        let res = unsafe {
+            getgrnam_r(c_name.as_ptr(), &mut grp, buf.as_mut_ptr(), cap as size_t, &mut result)
+        };
+        if res == ERANGE && cap < MAX_BUFFER {
+            buf.reserve(cap * 2);
+            continue
+        }
+        if res != 0 || result.is_null() {
+            return None
+        }
+        return f(&grp)
+    }
+}
+
+fn get_pwnam(c_user: &CString) -> Option<Passwd> {
+    get_pw(|pwd, buf, buflen, result| unsafe {
+        getpwnam_r(c_user.as_ptr(), pwd, buf, buflen, result)
+    })
+}
+
+// Calls one of the reentrant passwd functions, growing the buffer as needed.
+fn get_pw<F>(get: F) -> Option<Passwd>
+        where F: Fn(*mut passwd, *mut c_char, size_t, *mut *mut passwd) -> c_int {
+    let mut buf: Vec<c_char> = Vec::with_capacity(1024);
+    loop {
+        let mut pwd = passwd {
+            pw_name:   ptr::null_mut(),
+            pw_passwd: ptr::null_mut(),
+            pw_uid:    0,
+            pw_gid:    0,
+            pw_gecos:  ptr::null_mut(),
+            pw_dir:    ptr::null_mut(),
+            pw_shell:  ptr::null_mut(),
+        };
+        let mut result: *mut passwd = ptr::null_mut();
+        let cap = buf.capacity();
+        let res = get(&mut pwd, buf.as_mut_ptr(), cap as size_t, &mut result);
+        if res == ERANGE && cap < MAX_BUFFER {
+            buf.reserve(cap * 2);
+            continue
+        }
+        if res != 0 || result.is_null() {
+            return None
+        }
+        return unsafe {
+            to_string(pwd.pw_name).and_then(|name| {
+                to_string(pwd.pw_dir).and_then(|home| {
+                    to_string(pwd.pw_shell).map(|shell| {
+                        Passwd {
+                            name:   name,
+                            uid:    pwd.pw_uid,
+                            gid:    pwd.pw_gid,
+                            home:   Path::new(home),
+                            shell:  Path::new(shell),
+                            groups: Vec::new(),
+                        }
+                    })
+                })
+            })
+        }
+    }
+}
+
+fn get_groups(c_user: &CString, gid: gid_t) -> Option<Vec<gid_t>> {
+    let mut ngroups: c_int = 32;
+    loop {
+        let mut groups: Vec<gid_t> = Vec::with_capacity(ngroups as usize);
+        let mut n = ngroups;
+        let res = unsafe { getgrouplist(c_user.as_ptr(), gid, groups.as_mut_ptr(), &mut n) };
+        if res >= 0 {
+            unsafe { groups.set_len(n as usize) };
+            return Some(groups)
+        }
+        // `n` now holds the number of groups that the user is in.
+        if n <= ngroups || n as usize > MAX_BUFFER {
+            return None
+        }
+        ngroups = n;
+    }
+}
+
+unsafe fn to_string(p: *const c_char) -> Option<String> {
+    if p.is_null() {
+        return None
+    }
+    String::from_utf8(CStr::from_ptr(p).to_bytes().to_vec()).ok()
+}
@@ -0,0 +1,280 @@
+// Rules that decide, for each attempt, whether Tozny is required at all.  Rules
+// come from the system-wide file and are tried in order; the first rule whose
+// conditions all hold decides.  Without a matching rule, Tozny is required.
+//
+//     [[policy]]
+//     ttys   = ["tty*", ":0"]
+//     action = "skip"
+//
+//     [[policy]]
+//     services = ["sshd"]
+//     from     = ["10.8.0.0/16"]
+//     action   = "optional"
+
+use libc::{gid_t};
+use time;
+use toml;
+
+use cidr::{Cidr};
+use config;
+use config::{ConfigError};
+use passwd;
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Action {
+    /// Authenticate with Tozny as usual.
+    Require,
+    /// Leave the decision to the other modules in the stack (`PAM_IGNORE`).
+    Optional,
+    /// Let the user through without Tozny (`PAM_SUCCESS`).
+    Skip,
+}
+
+impl Action {
+    pub fn from_name(name: &str) -> Option<Action> {
+        match name {
+            "require"  => Some(Action::Require),
+            "optional" => Some(Action::Optional),
+            "skip"     => Some(Action::Skip),
+            _          => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Action::Require  => "require",
+            Action::Optional => "optional",
+            Action::Skip     => "skip",
+        }
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct Rule {
+    action:   Action,
+    services: Option<Vec<String>>,
+    from:     Option<Vec<Cidr>>,
+    ttys:     Option<Vec<String>>,
+    hours:    Option<Hours>,
+    groups:   Option<Vec<String>>,
+}
+
+// A time of day range in minutes after local midnight.  A range whose end comes
+// before its start wraps around midnight.
+#[derive(PartialEq, Debug)]
+struct Hours {
+    start: u32,
+    end:   u32,
+}
+
+/// What the rules may look at.
+pub struct Context<'a> {
+    pub service: Option<&'a str>,
+    pub rhost:   Option<&'a str>,
+    pub tty:     Option<&'a str>,
+    pub user:    &'a str,
+}
+
+/// Finds the first rule that matches `context`, and returns its position and
+/// action.
+pub fn decide(rules: &[Rule], context: &Context) -> Option<(usize, Action)> {
+    let now = time::now();
+    let minute = (now.tm_hour * 60 + now.tm_min) as u32;
+    // Only looked up if a rule asks about groups.
+    let mut groups: Option<Vec<gid_t>> = None;
+    for (i, rule) in rules.iter().enumerate() {
+        if rule.matches(context, minute, &mut groups) {
+            return Some((i, rule.action))
+        }
+    }
+    None
+}
+
+impl Rule {
+    fn from_value(value: &toml::Value) -> Result<Rule, ConfigError> {
+        let table = try!(value.as_table().ok_or_else(|| {
+            ConfigError::TypeError("Table", value.type_str())
+        }));
+        let action   = try!(config::get(table, "action").and_then(as_action));
+        let services = try!(config::get_opt(table, "services", config::as_names));
+        let from     = try!(config::get_opt(table, "from", config::as_ranges));
+        let ttys     = try!(config::get_opt(table, "ttys", config::as_names));
+        let hours    = try!(config::get_opt(table, "hours", as_hours));
+        let groups   = try!(config::get_opt(table, "groups", config::as_names));
+        Ok(Rule {
+            action:   action,
+            services: services,
+            from:     from,
+            ttys:     ttys,
+            hours:    hours,
+            groups:   groups,
+        })
+    }
+
+    // A condition on a value that the application did not report never holds.
+    fn matches(&self, context: &Context, minute: u32, groups: &mut Option<Vec<gid_t>>
+               ) -> bool {
+        let service_ok = config::matches(&self.services, context.service, |s, a| s == a);
+        let from_ok    = config::matches(&self.from, context.rhost, |h, r| r.contains(h));
+        let tty_ok     = config::matches(&self.ttys, context.tty, |t, p| tty_matches(t, p));
+        let hours_ok = self.hours.as_ref().map_or(true, |h| h.contains(minute));
+        service_ok && from_ok && tty_ok && hours_ok && match self.groups {
+            None           => true,
+            Some(ref list) => {
+                if groups.is_none() {
+                    *groups = Some(passwd::lookup(context.user)
+                                   .map(|pw| pw.groups).unwrap_or(Vec::new()));
+                }
+                let member_of = groups.as_ref().unwrap();
+                list.iter().filter_map(|g| passwd::group_id(g)).any(|gid| {
+                    member_of.iter().any(|m| *m == gid)
+                })
+            }
+        }
+    }
+}
+
+impl Hours {
+    fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            self.start <= minute && minute < self.end
+        }
+        else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+pub fn as_rules(v: &toml::Value) -> Result<Vec<Rule>, ConfigError> {
+    let rules = try!(config::as_slice(v));
+    rules.iter().map(Rule::from_value).collect()
+}
+
+fn as_action(v: &toml::Value) -> Result<Action, ConfigError> {
+    config::as_str(v).and_then(|name| {
+        Action::from_name(name).ok_or_else(|| {
+            ConfigError::InvalidValue("action".to_string(), name.to_string())
+        })
+    })
+}
+
+// Hours are given as "HH:MM-HH:MM", in local time.
+fn as_hours(v: &toml::Value) -> Result<Hours, ConfigError> {
+    let text = try!(config::as_str(v));
+    let invalid = || ConfigError::InvalidValue("hours".to_string(), text.to_string());
+    let parts: Vec<&str> = text.split('-').map(|p| p.trim()).collect();
+    if parts.len() != 2 {
+        return Err(invalid())
+    }
+    match (parse_minute(parts[0]), parse_minute(parts[1])) {
+        (Some(start), Some(end)) => Ok(Hours { start: start, end: end }),
+        _                        => Err(invalid()),
+    }
+}
+
+fn parse_minute(s: &str) -> Option<u32> {
+    let parts: Vec<&str> = s.split(':').collect();
+    if parts.len() != 2 {
+        return None
+    }
+    match (parts[0].parse::<u32>(), parts[1].parse::<u32>()) {
+        (Ok(h), Ok(m)) if h <= 24 && m < 60 && h * 60 + m <= 24 * 60 => Some(h * 60 + m),
+        _                                                            => None,
+    }
+}
+
+// Applications report the tty with or without "/dev/".  A pattern ending in
+// "*" matches any tty that starts with the rest of the pattern.
+fn tty_matches(tty: &str, pattern: &str) -> bool {
+    let tty = if tty.starts_with("/dev/") { &tty[5..] } else { tty };
+    let pattern = if pattern.starts_with("/dev/") { &pattern[5..] } else { pattern };
+    if pattern.ends_with("*") {
+        tty.starts_with(&pattern[..pattern.len() - 1])
+    }
+    else {
+        tty == pattern
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use toml;
+
+    use super::{as_rules, decide, tty_matches, Action, Context, Hours, Rule};
+
+    fn rules(input: &str) -> Vec<Rule> {
+        let table = toml::Parser::new(input).parse().expect("invalid TOML");
+        as_rules(table.get("policy").unwrap()).unwrap()
+    }
+
+    fn context<'a>(service: &'a str, rhost: Option<&'a str>, tty: Option<&'a str>
+                   ) -> Context<'a> {
+        Context { service: Some(service), rhost: rhost, tty: tty, user: "alice" }
+    }
+
+    const RULES: &'static str = r#"
+        [[policy]]
+        ttys   = ["tty*", ":0"]
+        action = "skip"
+
+        [[policy]]
+        services = ["sshd"]
+        from     = ["10.8.0.0/16"]
+        action   = "optional"
+
+        [[policy]]
+        services = ["sshd"]
+        action   = "require"
+    "#;
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let rules = rules(RULES);
+        assert_eq!(decide(&rules, &context("login", None, Some("/dev/tty1"))),
+                   Some((0, Action::Skip)));
+        assert_eq!(decide(&rules, &context("sshd", Some("10.8.3.4"), Some("ssh"))),
+                   Some((1, Action::Optional)));
+        assert_eq!(decide(&rules, &context("sshd", Some("198.51.100.7"), Some("ssh"))),
+                   Some((2, Action::Require)));
+    }
+
+    #[test]
+    fn unmatched_attempts_have_no_decision() {
+        let rules = rules(RULES);
+        assert_eq!(decide(&rules, &context("sudo", None, Some("pts/3"))), None);
+        assert_eq!(decide(&rules, &context("su", None, None)), None);
+    }
+
+    #[test]
+    fn hours_wrap_around_midnight() {
+        let night = Hours { start: 22 * 60, end: 6 * 60 };
+        assert!(night.contains(23 * 60));
+        assert!(night.contains(5 * 60 + 59));
+        assert!(!night.contains(6 * 60));
+        let day = Hours { start: 9 * 60, end: 17 * 60 };
+        assert!(day.contains(9 * 60));
+        assert!(!day.contains(17 * 60));
+    }
+
+    #[test]
+    fn matches_tty_patterns() {
+        assert!(tty_matches("/dev/tty2", "tty*"));
+        assert!(tty_matches("pts/0", "/dev/pts/*"));
+        assert!(tty_matches(":0", ":0"));
+        assert!(!tty_matches("pts/0", "tty*"));
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        for rule in [r#"services = ["sshd"]"#,
+                     r#"action = "maybe""#,
+                     r#"action = "skip"
+                        hours = "9-17""#,
+                     r#"action = "skip"
+                        hours = "22:00-25:00""#].iter() {
+            let input = format!("[[policy]]\n{}", rule);
+            let table = toml::Parser::new(&input).parse().expect("invalid TOML");
+            assert!(as_rules(table.get("policy").unwrap()).is_err(), "accepted {}", rule);
+        }
+    }
+}
@@ -0,0 +1,58 @@
+// Timing for polling the Tozny API while waiting for the user to approve a
+// login.
+
+use rand;
+use rand::Rng;
+use std::cmp;
+use std::time::Duration;
+
+// Number of empty responses at the regular interval before backing off.
+const BACKOFF_AFTER: usize = 10;
+
+// The backed-off delay never grows past this multiple of the regular interval.
+const MAX_FACTOR: i32 = 8;
+
+/// Produces the delay before each poll.  The first few delays are the
+/// configured interval.  After that each delay doubles, up to a limit, with
+/// random jitter so that many waiting clients do not poll in lockstep.
+pub struct Backoff {
+    interval: Duration,
+    current:  Duration,
+    empty:    usize,
+}
+
+impl Backoff {
+    pub fn new(interval: Duration) -> Backoff {
+        Backoff {
+            interval: interval,
+            current:  interval,
+            empty:    0,
+        }
+    }
+
+    /// Call after each empty response to get the time to wait before the next
+    /// poll.
+    pub fn next_delay(&mut self) -> Duration {
+        self.empty += 1;
+        if self.empty <= BACKOFF_AFTER {
+            return self.interval
+        }
+        self.current = cmp::min(self.current * 2, self.interval * MAX_FACTOR);
+        jitter(self.current)
+    }
+
+    /// Returns to the regular interval, for example after the user has
+    /// interacted with the prompt.
+    pub fn reset(&mut self) {
+        self.current = self.interval;
+        self.empty   = 0;
+    }
+}
+
+// Randomly shortens or lengthens `delay` by up to a quarter.
+fn jitter(delay: Duration) -> Duration {
+    let ms     = delay.num_milliseconds();
+    let spread = cmp::max(ms / 4, 1);
+    let offset = rand::thread_rng().gen_range(-spread, spread + 1);
+    Duration::milliseconds(ms + offset)
+}
@@ -1,77 +1,99 @@
-use std::iter::{IteratorExt};
-use std::{old_io, str};
-use std::old_io::{fs, Command, FileAccess, FileMode, FileType, Reader};
-use std::old_path::{GenericPath};
-use std::old_path::posix::{Path};
+// The presence token lets the module send push notifications to the user's
+// phone.  It is cached in `~/.cache/toznyauth_pam_presence`.
+//
+// The module runs as root under sshd and sudo, but the cache directory is
+// controlled by the user.  So every file is opened relative to a directory
+// descriptor that has been checked, symlinks are never followed, and the file
+// is replaced atomically.
+
+use libc::{getpid, mode_t};
+use rand;
+use rand::Rng;
 use tozny_auth::protocol::{Newtype, Presence};
 
-pub fn get_presence(home: &Path) -> Option<Presence> {
-    get_presence_file(home)
-    .and_then(|path| {
-        fs::File::open(&path).ok()
+use fd;
+use fd::{Errno, Fd, FsIds};
+use passwd::{Passwd};
+
+const CACHE_DIR:     &'static str = ".cache";
+const PRESENCE_FILE: &'static str = "toznyauth_pam_presence";
+
+/// Reads the cached presence token.  With `as_user`, file operations are
+/// performed with the filesystem ids of the account.
+pub fn get_presence(account: &Passwd, as_user: bool) -> Option<Presence> {
+    let _ids = if as_user { Some(FsIds::switch(account.uid, account.gid)) } else { None };
+    open_cache_dir(account, false)
+    .and_then(|dir| {
+        dir.openat(PRESENCE_FILE, fd::O_RDONLY | fd::O_NOFOLLOW, 0)
     })
-    .and_then(|mut file| {
-        file.read_to_string().ok()
+    .and_then(|file| {
+        check_owned(&file, account, fd::S_IFREG).map(|_| file)
     })
+    .and_then(|file| file.read_to_end())
+    .ok()
+    .and_then(|bytes| String::from_utf8(bytes).ok())
     .map(Presence::new)
 }
 
-pub fn save_presence(user: &str, home: &Path, presence: &Presence) {
-    get_presence_file(home)
-    .and_then(|path| {
-        fs::File::open_mode(&path, FileMode::Truncate, FileAccess::Write).ok()
-        .and_then(|mut file| {
-            file.write_str(presence.as_slice()).ok()
-        });
-
-        // If this module is used for `sudo` authentication, presence file will
-        // be in user's home directory, but will be owned by root.
-        get_id("-u", user)
-        .and_then(|uid| {
-            get_id("-g", user).map(|gid| {
-                let _ = fs::chown(&path, uid, gid);
-            })
+/// Caches a presence token, replacing any previous token.
+pub fn save_presence(account: &Passwd, as_user: bool, presence: &Presence) -> Result<(), Errno> {
+    let _ids = if as_user { Some(FsIds::switch(account.uid, account.gid)) } else { None };
+    let dir = try!(open_cache_dir(account, true));
+    let tmp = format!("{}.{}.{}", PRESENCE_FILE,
+                      unsafe { getpid() }, rand::thread_rng().gen::<u32>());
+    let written = dir.openat(&tmp, fd::O_WRONLY | fd::O_CREAT | fd::O_EXCL | fd::O_NOFOLLOW,
+                             0o600)
+    .and_then(|file| {
+        file.write_all(presence.as_slice().as_bytes())
+        .and_then(|_| file.fchmod(0o600))
+        .and_then(|_| {
+            // When running as root, the new file is owned by root.
+            if as_user { Ok(()) } else { file.fchown(account.uid, account.gid) }
         })
-    });
+        .and_then(|_| file.fsync())
+    })
+    .and_then(|_| dir.renameat(&tmp, PRESENCE_FILE));
+    if written.is_err() {
+        let _ = dir.unlinkat(&tmp);
+    }
+    written
 }
 
-
-pub fn get_presence_file(home: &Path) -> Option<Path> {
-    let mut file = home.clone();
-    file.push(".cache");
-    match fs::stat(&file) {
-        Ok(stat) => {
-            if stat.kind == FileType::Directory {
-                Some(())
-            }
-            else {
-                None
+// Opens `~/.cache` without following a symlink, creating it if `create` is
+// set, and checks that it belongs to the account and that no one else can
+// write to it.
+fn open_cache_dir(account: &Passwd, create: bool) -> Result<Fd, Errno> {
+    let home = try!(Fd::open(&account.home, fd::O_RDONLY | fd::O_DIRECTORY, 0));
+    let stat = try!(home.fstat());
+    if stat.st_uid != account.uid && stat.st_uid != 0 {
+        return Err(fd::EACCES)
+    }
+    let flags = fd::O_RDONLY | fd::O_DIRECTORY | fd::O_NOFOLLOW;
+    let dir = match home.openat(CACHE_DIR, flags, 0) {
+        Err(fd::ENOENT) if create => {
+            try!(home.mkdirat(CACHE_DIR, 0o700).or_else(|e| {
+                if e == fd::EEXIST { Ok(()) } else { Err(e) }
+            }));
+            let dir = try!(home.openat(CACHE_DIR, flags, 0));
+            let stat = try!(dir.fstat());
+            if stat.st_uid == 0 && account.uid != 0 {
+                try!(dir.fchown(account.uid, account.gid));
             }
-        },
-        Err(_) => {
-            fs::mkdir(&file, old_io::USER_RWX).ok().map(|_| ())
+            dir
         }
-    }
-    .map(|_| {
-        file.push("toznyauth_pam_presence");
-        file
-    })
+        other => try!(other),
+    };
+    try!(check_owned(&dir, account, fd::S_IFDIR));
+    Ok(dir)
 }
 
-fn get_id(flag: &str, user: &str) -> Option<isize> {
-    Command::new("id").arg(flag).arg(user).output().ok()
-    .and_then(|out| {
-        if out.status.success() {
-            Some(out.output)
-        }
-        else {
-            None
-        }
-    })
-    .and_then(|bytes| {
-        String::from_utf8(bytes).ok()
-    })
-    .and_then(|s| {
-        str::FromStr::from_str(s.as_slice()).ok()
-    })
+fn check_owned(file: &Fd, account: &Passwd, kind: mode_t) -> Result<(), Errno> {
+    let stat = try!(file.fstat());
+    if fd::owned_by(stat.st_uid, stat.st_mode, &[account.uid]) &&
+       stat.st_mode & fd::S_IFMT == kind {
+        Ok(())
+    }
+    else {
+        Err(fd::EACCES)
+    }
 }
@@ -0,0 +1,104 @@
+// A prompt that runs on its own thread, so that the module can keep polling for
+// approval while the application waits for the user to type a response.
+//
+// Conversation functions are not re-entrant, and the application may free the
+// conversation once the module returns, so a prompt must be answered before
+// anything else is sent and before the module returns.  Dropping a `Prompt`
+// waits for the answer.
+
+use pam::constants::*;
+use std::cell::{Cell};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+
+use conv::{Conversation};
+
+/// Text appended to messages that are sent as prompts.
+pub const INSTRUCTIONS: &'static str =
+    "Once you have approved the login, press Enter to continue. \
+    Or type r to resend the push notification, qr to show a QR code, or q to cancel:";
+
+/// Commands that the user can type at a prompt.
+pub enum Command {
+    /// Keep waiting for approval.
+    Continue,
+    /// Give up on authentication.
+    Cancel,
+    /// Send the push notification again.
+    ResendPush,
+    /// Show the QR code for the current login challenge.
+    ShowQr,
+    Unknown(String),
+}
+
+pub fn parse_command(line: &str) -> Command {
+    match line.trim() {
+        ""           => Command::Continue,
+        "q" | "Q"    => Command::Cancel,
+        "r" | "R"    => Command::ResendPush,
+        "qr" | "QR"  => Command::ShowQr,
+        other        => Command::Unknown(other.to_string()),
+    }
+}
+
+/// The result of checking on a prompt.
+pub enum Response {
+    /// The user has not answered yet.
+    Waiting,
+    /// The user entered a line of text.
+    Line(String),
+    /// The conversation failed, for example because the client disconnected.
+    Failed(PamResultCode),
+}
+
+pub struct Prompt {
+    rx:       Receiver<Result<String, PamResultCode>>,
+    answered: Cell<bool>,
+}
+
+// The conversation belongs to the application, which keeps it alive until the
+// module returns.  The prompt is always answered before then.
+struct ConvPtr<C>(*const C);
+unsafe impl<C> Send for ConvPtr<C> {}
+
+impl Prompt {
+    /// Sends `msg` as a `PAM_PROMPT_ECHO_OFF` message on a new thread.
+    pub fn spawn<C: Conversation + 'static>(conv: &C, msg: String) -> Prompt {
+        let (tx, rx) = channel();
+        let ptr = ConvPtr(conv as *const C);
+        thread::spawn(move || {
+            let ConvPtr(p) = ptr;
+            let conv = unsafe { &*p };
+            let res = conv.send(PAM_PROMPT_ECHO_OFF, &msg)
+                .map(|line| line.unwrap_or(String::new()));
+            let _ = tx.send(res);
+        });
+        Prompt { rx: rx, answered: Cell::new(false) }
+    }
+
+    /// Checks for an answer without blocking.
+    pub fn try_response(&self) -> Response {
+        let response = match self.rx.try_recv() {
+            Ok(Ok(line))                      => Response::Line(line),
+            Ok(Err(code))                     => Response::Failed(code),
+            Err(TryRecvError::Empty)          => Response::Waiting,
+            Err(TryRecvError::Disconnected)   => Response::Failed(PAM_CONV_ERR),
+        };
+        if let Response::Waiting = response {} else { self.answered.set(true) }
+        response
+    }
+
+    /// Whether the conversation function is still waiting for the user.
+    pub fn is_open(&self) -> bool {
+        !self.answered.get()
+    }
+}
+
+impl Drop for Prompt {
+    // Once the thread has sent its answer, it no longer uses the conversation.
+    fn drop(&mut self) {
+        if self.is_open() {
+            let _ = self.rx.recv();
+        }
+    }
+}
@@ -0,0 +1,80 @@
+// Session records tie a unix session back to the Tozny identity that opened it.
+// This matters for shared accounts, where the unix user alone does not say who
+// logged in.
+
+use pam::constants::*;
+use pam::module::{PamHandleT};
+use time;
+use tozny_auth::login::{Login};
+use tozny_auth::protocol::{Newtype};
+
+use logger::{or_unknown};
+use pam_ext;
+
+/// Information about the PAM transaction that is included in each record.
+pub struct SessionInfo {
+    pub unix_user: String,
+    pub service:   Option<String>,
+    pub rhost:     Option<String>,
+    pub tty:       Option<String>,
+}
+
+impl SessionInfo {
+    pub fn from_handle(pamh: &PamHandleT, unix_user: &str) -> SessionInfo {
+        SessionInfo {
+            unix_user: unix_user.to_string(),
+            service:   pam_ext::get_str_item(pamh, PAM_SERVICE),
+            rhost:     pam_ext::get_str_item(pamh, PAM_RHOST),
+            tty:       pam_ext::get_str_item(pamh, PAM_TTY),
+        }
+    }
+}
+
+/// The Tozny user behind a session.
+pub struct Identity {
+    pub user_id:      String,
+    pub user_display: String,
+    /// Whether the identity was read back from the PAM environment, rather
+    /// than from a login verified in this process.
+    pub from_env:     bool,
+}
+
+impl Identity {
+    pub fn from_login(login: &Login) -> Identity {
+        Identity {
+            user_id:      login.user_id.as_slice().to_string(),
+            user_display: login.user_display.clone(),
+            from_env:     false,
+        }
+    }
+}
+
+/// Time at which the session was opened, stored on the PAM handle so that the
+/// close record can report the session duration.
+pub struct SessionStart(pub time::Timespec);
+
+pub fn record_open(pamh: &PamHandleT, info: &SessionInfo, identity: &Identity) {
+    pam_ext::syslog(pamh, pam_ext::LOG_INFO, &format!(
+        "session opened for user {} by Tozny user {} ({}){}",
+        info.unix_user, identity.user_id, identity.user_display, details(info, identity)));
+}
+
+pub fn record_close(pamh: &PamHandleT, info: &SessionInfo, identity: &Identity,
+                    start: Option<&SessionStart>) {
+    let duration = start.map(|&SessionStart(t)| {
+        format!(" duration={}s", (time::get_time() - t).num_seconds())
+    })
+    .unwrap_or(String::new());
+    pam_ext::syslog(pamh, pam_ext::LOG_INFO, &format!(
+        "session closed for user {} by Tozny user {} ({}){}{}",
+        info.unix_user, identity.user_id, identity.user_display, details(info, identity),
+        duration));
+}
+
+// Identities read from the environment are marked, because another module in
+// the stack could have set the variables.
+fn details(info: &SessionInfo, identity: &Identity) -> String {
+    format!(" service={} rhost={} tty={}{}",
+            or_unknown(&info.service), or_unknown(&info.rhost), or_unknown(&info.tty),
+            if identity.from_env { " source=env" } else { "" })
+}
@@ -11,6 +11,9 @@ extern crate libc;
 extern crate mdo;
 extern crate pam;
 extern crate qrcode;
+extern crate rand;
+extern crate rustc_serialize;
+extern crate time;
 extern crate toml;
 extern crate tozny_auth;
 extern crate url;
@@ -20,198 +23,820 @@ use mdo::result::{bind};
 use pam::{constants, module};
 use pam::conv::{PamConv};
 use pam::constants::*;
-use std::{ffi, fmt, num, str};
+use qrcode::types::{QrError};
+use std::{cmp, ffi, fmt, num, thread};
 use std::old_io::timer::sleep;
 use std::old_io::{Writer};
+use std::old_path::posix::{Path};
 use std::time::Duration;
-use tozny_auth::{login, protocol, question, user};
-use tozny_auth::protocol::{Newtype};
+use tozny_auth::{login, protocol, question};
+use tozny_auth::protocol::{Newtype, Presence};
 
-use config::{Config, ConfigError};
+use authorized::{Attempt, Denial};
+use backend::{Backend, Challenge};
+use config::{Config, ConfigError, OnUnavailable, QrStyle, Trusted};
+use conv::{Conversation};
+use logger::{Logger, Method};
+use policy::{Action, Context};
+use prompt::{Command, Prompt, Response};
 
+mod audit;
+mod authorized;
+mod backend;
+mod cidr;
 mod config;
+mod conv;
+mod fd;
+mod invoker;
+mod logger;
+mod login_data;
 #[macro_use] mod my_mdo;
+mod pam_ext;
+mod passwd;
+mod policy;
+mod poll;
 mod presence;
+mod prompt;
 mod qr_term;
+mod session;
+mod user_map;
+
+// Key under which the time that a session was opened is stored.
+const SESSION_START: &'static str = "toznyauth_pam_session_start";
+
+// Key under which the `on_unavailable` policy is stored when the auth phase
+// falls back to it, so that the account phase can follow suit.
+const UNAVAILABLE: &'static str = "toznyauth_pam_unavailable";
+
+// Key under which the policy action is stored when a `[[policy]]` rule exempts
+// the attempt from Tozny, so that the account phase can follow suit.
+const EXEMPT: &'static str = "toznyauth_pam_exempt";
+
+// Variables exported to the PAM environment after successful authentication.
+const ENV_USER_ID:      &'static str = "TOZNY_USER_ID";
+const ENV_USER_DISPLAY: &'static str = "TOZNY_USER_DISPLAY";
+const ENV_SESSION_ID:   &'static str = "TOZNY_SESSION_ID";
+const ENV_REALM:        &'static str = "TOZNY_REALM";
+
+// Every exported function runs its implementation through `guarded`, because a
+// panic unwinding into the application (sshd, sudo) would abort it.
 
 #[no_mangle]
 pub extern fn pam_sm_authenticate(pamh: &module::PamHandleT, flags: PamFlag,
                                   argc: c_int, argv: *const *const c_char
                                   ) -> PamResultCode {
-    let args = unsafe { translate_args(argc, argv) };
+    guarded("pam_sm_authenticate", sm_authenticate, pamh, flags, argc, argv)
+}
+
+#[no_mangle]
+pub extern fn pam_sm_setcred(pamh: &module::PamHandleT, flags: PamFlag,
+                             argc: c_int, argv: *const *const c_char
+                             ) -> PamResultCode {
+    guarded("pam_sm_setcred", sm_setcred, pamh, flags, argc, argv)
+}
+
+#[no_mangle]
+pub extern fn pam_sm_acct_mgmt(pamh: &module::PamHandleT, flags: PamFlag,
+                               argc: c_int, argv: *const *const c_char
+                               ) -> PamResultCode {
+    guarded("pam_sm_acct_mgmt", sm_acct_mgmt, pamh, flags, argc, argv)
+}
+
+#[no_mangle]
+pub extern fn pam_sm_open_session(pamh: &module::PamHandleT, flags: PamFlag,
+                                  argc: c_int, argv: *const *const c_char
+                                  ) -> PamResultCode {
+    guarded("pam_sm_open_session", sm_open_session, pamh, flags, argc, argv)
+}
+
+#[no_mangle]
+pub extern fn pam_sm_close_session(pamh: &module::PamHandleT, flags: PamFlag,
+                                   argc: c_int, argv: *const *const c_char
+                                   ) -> PamResultCode {
+    guarded("pam_sm_close_session", sm_close_session, pamh, flags, argc, argv)
+}
+
+fn sm_authenticate(pamh: &module::PamHandleT, flags: PamFlag, args: &[String]
+                   ) -> PamResultCode {
+    login_data::clear(pamh);
+    unexport_login(pamh);
+    let _ = pam_ext::set_data::<OnUnavailable>(pamh, UNAVAILABLE, None);
+    let _ = pam_ext::set_data::<Action>(pamh, EXEMPT, None);
+    let user = match module::get_user(pamh, None) {
+        Ok(user)  => user,
+        Err(code) => return code,
+    };
+    let log = Logger::new(pamh, user.as_slice());
+    let trusted = Trusted::read(args);
+    // Decisions on configuration errors are audited too.
+    let audit_log = trusted.as_ref().ok().and_then(|t| t.audit_log());
+    if let Some(action) = exemption(&trusted, &log) {
+        let _ = pam_ext::set_data(pamh, EXEMPT, Some(Box::new(action)));
+        return exempt_code(&audit_log, &log, "auth", action)
+    }
+    let on_unavailable = trusted.as_ref().ok().and_then(|t| t.on_unavailable());
+    let config = build_config(pamh, user.as_slice(), &trusted, &log);
     let decision = mdo! {
-        user   =<< module::get_user(pamh, None).map_err(AuthError::PamResult);
-        config =<< Config::build(user.as_slice(), args.as_slice())
-            .map_err(AuthError::ConfigError);
+        config =<< config;
+        let _ = log.set_debug(config.debug);
+        ign check_target(&config, user.as_slice());
         conv   =<< module::get_item::<PamConv>(pamh).map_err(AuthError::PamResult);
-        login  =<< authenticate(&config, user.as_slice(), &conv);
+        let api = config.get_user_api();
+        login  =<< authenticate(&config, &api, conv, &log);
         ign show_info(conv, flags, &format!("Authenticated as {}", login.user_display));
+        ign export_login(pamh, &config, &login).map_err(AuthError::PamResult);
+        ign login_data::store(pamh, login).map_err(AuthError::PamResult);
         ret Ok(constants::PAM_SUCCESS)
     };
 
-    decision.unwrap_or_else(|e| {
-        if flags & constants::PAM_SILENT == 0 {
-            show_err(pamh, &e)
+    let (code, outcome_name) = match decision {
+        Ok(code) => {
+            log.notice("authentication succeeded");
+            (code, outcome(&decision))
         }
-        error_code(&e)
-    })
+        Err(ref e) if is_unavailable(e) && on_unavailable.is_some() => {
+            let policy = on_unavailable.unwrap();
+            log.warning(&format!("authentication unavailable: {}; on_unavailable={}",
+                                 e, policy.name()));
+            if policy == OnUnavailable::Deny && flags & constants::PAM_SILENT == 0 {
+                show_err(pamh, e)
+            }
+            let _ = pam_ext::set_data(pamh, UNAVAILABLE, Some(Box::new(policy)));
+            (unavailable_code(policy), unavailable_outcome(policy))
+        }
+        Err(ref e) => {
+            log_failure(&log, "authentication", e);
+            if flags & constants::PAM_SILENT == 0 {
+                show_err(pamh, e)
+            }
+            (error_code(e), outcome(&decision))
+        }
+    };
+    for path in audit_log.iter() {
+        audit::record(path, &log, "auth", outcome_name, code);
+    }
+    code
 }
 
+// This module does not manage credentials of its own.  Credentials are only
+// reported as established if the auth phase verified a Tozny login; otherwise
+// the module stays out of the way.
 #[allow(unused_variables)]
-#[no_mangle]
-pub extern fn pam_sm_setcred(pamh: *mut module::PamHandleT, flags: PamFlag,
-                             argc: c_int, argv: *const *const c_char
-                             ) -> PamResultCode {
-    constants::PAM_SUCCESS
+fn sm_setcred(pamh: &module::PamHandleT, flags: PamFlag, args: &[String]) -> PamResultCode {
+    if flags & constants::PAM_DELETE_CRED != 0 {
+        login_data::clear(pamh);
+        unexport_login(pamh);
+        return constants::PAM_SUCCESS
+    }
+    match login_data::get(pamh) {
+        Some(_) => constants::PAM_SUCCESS,
+        None    => constants::PAM_IGNORE,
+    }
+}
+
+fn sm_acct_mgmt(pamh: &module::PamHandleT, flags: PamFlag, args: &[String]) -> PamResultCode {
+    let user = match module::get_user(pamh, None) {
+        Ok(user)  => user,
+        Err(code) => return code,
+    };
+    let log = Logger::new(pamh, user.as_slice());
+    let trusted = Trusted::read(args);
+    let audit_log = trusted.as_ref().ok().and_then(|t| t.audit_log());
+    // The auth phase let the user through without Tozny, and without reading
+    // the rest of the configuration.
+    if let Some(action) = pam_ext::get_data::<Action>(pamh, EXEMPT).map(|a| *a) {
+        return exempt_code(&audit_log, &log, "account", action)
+    }
+    let config = build_config(pamh, user.as_slice(), &trusted, &log);
+    let decision = mdo! {
+        login  =<< login_data::get(pamh).ok_or(AuthError::NoLogin);
+        config =<< config;
+        let _ = log.set_debug(config.debug);
+        ign check_target(&config, user.as_slice());
+        let _ = log.set_tozny_user(login.user_id.as_slice());
+        let _ = log.set_session_id(login.session_id.as_slice());
+        ign check_account(&config, login, attempt(&log));
+        ret Ok(constants::PAM_SUCCESS)
+    };
+
+    let unavailable = pam_ext::get_data::<OnUnavailable>(pamh, UNAVAILABLE).map(|p| *p);
+    let code = match decision {
+        Ok(code) => {
+            log.info("account check succeeded");
+            code
+        }
+        // The auth phase let the user through without a Tozny login.
+        Err(AuthError::NoLogin) if unavailable.is_some() => {
+            let policy = unavailable.unwrap();
+            log.info(&format!("no Tozny login; on_unavailable={}", policy.name()));
+            unavailable_code(policy)
+        }
+        // The auth phase did not run in this process: another method, such as
+        // an ssh key, was used, or sshd ran it in a child process whose data
+        // is gone.  There is no login to check, so other modules decide.
+        Err(AuthError::NoLogin) => {
+            log.info("no Tozny login from the auth phase of this process; ignoring");
+            PAM_IGNORE
+        }
+        Err(ref e) => {
+            log_failure(&log, "account check", e);
+            if flags & constants::PAM_SILENT == 0 {
+                show_err(pamh, e)
+            }
+            account_error_code(e)
+        }
+    };
+    for path in audit_log.iter() {
+        audit::record(path, &log, "account", outcome(&decision), code);
+    }
+    code
+}
+
+#[allow(unused_variables)]
+fn sm_open_session(pamh: &module::PamHandleT, flags: PamFlag, args: &[String]
+                   ) -> PamResultCode {
+    let decision = mdo! {
+        user     =<< module::get_user(pamh, None).map_err(AuthError::PamResult);
+        identity =<< session_identity(pamh).ok_or(AuthError::NoLogin);
+        let info = session::SessionInfo::from_handle(pamh, user.as_slice());
+        let _ = session::record_open(pamh, &info, &identity);
+        let start = session::SessionStart(time::get_time());
+        ign pam_ext::set_data(pamh, SESSION_START, Some(Box::new(start)))
+            .map_err(AuthError::PamResult);
+        ret Ok(constants::PAM_SUCCESS)
+    };
+    decision.unwrap_or_else(|e| session_error_code(&e))
+}
+
+#[allow(unused_variables)]
+fn sm_close_session(pamh: &module::PamHandleT, flags: PamFlag, args: &[String]
+                    ) -> PamResultCode {
+    let decision = mdo! {
+        user     =<< module::get_user(pamh, None).map_err(AuthError::PamResult);
+        identity =<< session_identity(pamh).ok_or(AuthError::NoLogin);
+        let info  = session::SessionInfo::from_handle(pamh, user.as_slice());
+        let start = pam_ext::get_data::<session::SessionStart>(pamh, SESSION_START);
+        let _ = session::record_close(pamh, &info, &identity, start);
+        ret Ok(constants::PAM_SUCCESS)
+    };
+    decision.unwrap_or_else(|e| session_error_code(&e))
+}
+
+// The Tozny user behind a session: the login verified by the auth phase in this
+// process, or else the one it exported to the PAM environment.  sshd runs the
+// auth phase in a child process, and copies the child's PAM environment, but
+// not its module data, back to the handle used for the session.
+fn session_identity(pamh: &module::PamHandleT) -> Option<session::Identity> {
+    if let Some(login) = login_data::get(pamh) {
+        return Some(session::Identity::from_login(login))
+    }
+    pam_ext::getenv(pamh, ENV_USER_ID).map(|user_id| {
+        let user_display = pam_ext::getenv(pamh, ENV_USER_DISPLAY)
+            .unwrap_or(user_id.clone());
+        session::Identity {
+            user_id:      user_id,
+            user_display: user_display,
+            from_env:     true,
+        }
+    })
+}
+
+// Reads the configuration of the user to authenticate.  That is the target
+// account, unless the `invoking_user` option asks for the user who ran sudo or
+// su.  Other services have no invoking user, so the option does not apply to
+// them even when it is set in the system-wide file.
+fn build_config(pamh: &module::PamHandleT, target: &str,
+                trusted: &Result<Trusted, ConfigError>, log: &Logger
+                ) -> Result<Config, AuthError> {
+    let trusted = try!(trusted.as_ref().map_err(|e| AuthError::ConfigError(e.clone())));
+    let service = log.service.as_ref().map(|s| s.as_slice());
+    let user = if trusted.invoking_user() && invoker::applies_to(service) {
+        let user = try!(invoker::invoking_user(pamh).ok_or(AuthError::NoInvokingUser));
+        log.info(&format!("authenticating invoking user {}", user));
+        user
+    }
+    else {
+        target.to_string()
+    };
+    Config::build(user.as_slice(), trusted).map_err(AuthError::ConfigError)
+}
+
+// When authenticating the invoking user, `allowed_targets` limits which
+// accounts they may become.
+fn check_target(config: &config::Config, target: &str) -> Result<(), AuthError> {
+    if config.account.name == target || config.may_become(target) {
+        Ok(())
+    }
+    else {
+        Err(AuthError::TargetNotAllowed(target.to_string()))
+    }
 }
 
 #[derive(Debug)]
 enum AuthError {
+    Cancelled,
     ConfigError(ConfigError),
-    NotAuthorized,
+    InvalidArguments,
+    InvalidUtf8,
+    LoginExpired,
+    NoInvokingUser,
+    NoLogin,
+    NotAuthorized(Denial),
     PamResult(PamResultCode),
+    QrError(QrError),
+    TargetNotAllowed(String),
     TimedOut,
     QuestionError(question::QuestionError),
+    Unavailable(question::QuestionError),
 }
 
-fn authenticate(config: &config::Config,
-                user: &str,
-                conv: &PamConv,
-                ) -> Result<login::Login, AuthError> {
-    let user_api = config.get_user_api();
-    let q        = AuthError::QuestionError;
+fn authenticate<C: Conversation + 'static>(config: &config::Config,
+                                           api: &Backend,
+                                           conv: &C,
+                                           log: &Logger,
+                                           ) -> Result<login::Login, AuthError> {
+    let q = AuthError::QuestionError;
 
-    user_api.login_challenge().map_err(q)
+    api.login_challenge().map_err(api_error)
     .and_then(|challenge| {
-        let did_push = if config.presence {
-            let d = push_notification(&user_api, config, &challenge.session_id);
-            let _ = presence::save_presence(user, &config.home_dir, &challenge.presence);
-            d
-        } else { false };
-        if did_push { show_push(config, conv) } else { show_qr(config, conv, &challenge) }
-        .and_then(|_| {
-            poll_session_status(&user_api, &challenge, 100)  // TODO: configurable TTL
+        log.set_session_id(challenge.session_id.as_slice());
+        log.info(&format!("issued login challenge for session {}",
+                          challenge.session_id.as_slice()));
+        // The cached token is replaced by the challenge's own token, which
+        // only becomes usable once this login completes, so a resend goes to
+        // the token used for the first push.
+        let pushed_to = if config.presence {
+            let p = push_notification(api, config, &challenge.session_id, log);
+            let _ = presence::save_presence(&config.account, config.presence_as_user,
+                                            &challenge.presence);
+            p
+        } else { None };
+        if pushed_to.is_some() {
+            log.set_method(Method::Push);
+            log.info("sent push notification");
+            show_push(config, conv)
+        }
+        else {
+            log.set_method(Method::Qr);
+            log.info("showing QR code");
+            show_qr(config, conv, &challenge)
+        }
+        .and_then(|prompt| {
+            poll_session_status(config, conv, api, &challenge, prompt, pushed_to.as_ref(), log)
         })
     })
-    .and_then(|question| {
-        question::unpack::<login::Login>(&question.signed_data).map_err(q)
+    .and_then(|signed_data| {
+        log.debug("received signed login");
+        api.unpack(&signed_data).map_err(q)
     })
     .and_then(|login| {
-        if config.is_authorized(&login) { Ok(login) } else { Err(AuthError::NotAuthorized) }
+        log.set_tozny_user(login.user_id.as_slice());
+        log.info(&format!("login approved by {}", login.user_display));
+        config.is_authorized(&login, attempt(log)).map(|_| login)
+        .map_err(AuthError::NotAuthorized)
     })
 }
 
-fn push_notification(user_api: &user::UserApi,
+// Short name for the outcome of a decision, for the audit log.
+fn outcome<T>(decision: &Result<T, AuthError>) -> &'static str {
+    match decision {
+        &Ok(_)                                 => "success",
+        &Err(AuthError::Cancelled)             => "cancelled",
+        &Err(AuthError::ConfigError(_))        => "config_error",
+        &Err(AuthError::InvalidArguments)      => "service_error",
+        &Err(AuthError::InvalidUtf8)           => "service_error",
+        &Err(AuthError::LoginExpired)          => "expired",
+        &Err(AuthError::NoInvokingUser)        => "no_invoking_user",
+        &Err(AuthError::NoLogin)               => "no_login",
+        &Err(AuthError::NotAuthorized(_))      => "not_authorized",
+        &Err(AuthError::PamResult(_))          => "pam_error",
+        &Err(AuthError::QrError(_))            => "service_error",
+        &Err(AuthError::TargetNotAllowed(_))   => "target_not_allowed",
+        &Err(AuthError::TimedOut)              => "timed_out",
+        &Err(AuthError::QuestionError(ref e))  => match e {
+            &question::QuestionError::InvalidSignature => "invalid_signature",
+            _                                          => "api_error",
+        },
+        &Err(AuthError::Unavailable(_))        => "unavailable",
+    }
+}
+
+// Failures that say nothing about whether the user is who they claim to be,
+// which the `on_unavailable` policy applies to.
+fn is_unavailable(err: &AuthError) -> bool {
+    match err {
+        &AuthError::Unavailable(_)                               => true,
+        &AuthError::ConfigError(ConfigError::MissingAuthFile(_)) => true,
+        &AuthError::ConfigError(ConfigError::NoHomeDir)          => true,
+        _                                                        => false,
+    }
+}
+
+// Only a failure to talk to the API at all, such as a refused connection or a
+// network timeout, means that Tozny is unavailable.  An error response from the
+// API, such as a denied login, is a verdict like any other.
+fn api_error(err: question::QuestionError) -> AuthError {
+    match err {
+        question::QuestionError::HttpError(_) => AuthError::Unavailable(err),
+        _                                     => AuthError::QuestionError(err),
+    }
+}
+
+fn unavailable_code(policy: OnUnavailable) -> PamResultCode {
+    match policy {
+        OnUnavailable::Deny    => PAM_AUTH_ERR,
+        OnUnavailable::Ignore  => PAM_IGNORE,
+        OnUnavailable::Success => PAM_SUCCESS,
+    }
+}
+
+fn unavailable_outcome(policy: OnUnavailable) -> &'static str {
+    match policy {
+        OnUnavailable::Deny    => "unavailable_deny",
+        OnUnavailable::Ignore  => "unavailable_ignore",
+        OnUnavailable::Success => "unavailable_success",
+    }
+}
+
+// Applies the `[[policy]]` rules to this attempt.  Returns the action if a rule
+// exempts the attempt from Tozny.  Rules that cannot be read require Tozny.
+fn exemption(trusted: &Result<Trusted, ConfigError>, log: &Logger) -> Option<Action> {
+    let rules = match trusted.as_ref().and_then(|t| t.policy()) {
+        Ok(rules) => rules,
+        Err(e)    => {
+            log.err(&format!("could not read policy, requiring Tozny: {}", e));
+            return None
+        }
+    };
+    let context = Context {
+        service: log.service.as_ref().map(|s| s.as_slice()),
+        rhost:   log.rhost.as_ref().map(|h| h.as_slice()),
+        tty:     log.tty.as_ref().map(|t| t.as_slice()),
+        user:    log.unix_user.as_slice(),
+    };
+    match policy::decide(rules, &context) {
+        Some((i, action)) => {
+            log.info(&format!("policy rule {} applies: {}", i + 1, action.name()));
+            if action == Action::Require { None } else { Some(action) }
+        }
+        None => None,
+    }
+}
+
+fn exempt_code(audit_log: &Option<Path>, log: &Logger, phase: &'static str, action: Action
+               ) -> PamResultCode {
+    let (code, outcome_name) = match action {
+        Action::Skip => (PAM_SUCCESS, "policy_skip"),
+        _            => (PAM_IGNORE, "policy_optional"),
+    };
+    for path in audit_log.iter() {
+        audit::record(path, log, phase, outcome_name, code);
+    }
+    code
+}
+
+// Service failures are errors; the user failing to authenticate is not.
+fn log_failure(log: &Logger, phase: &str, err: &AuthError) {
+    let msg = match err {
+        &AuthError::PamResult(code) => format!("{} failed: PAM error {}", phase, code),
+        _                           => format!("{} failed: {}", phase, err),
+    };
+    match error_code(err) {
+        PAM_SERVICE_ERR | PAM_AUTHINFO_UNAVAIL => log.err(&msg),
+        _                                      => log.warning(&msg),
+    }
+}
+
+// Exposes the Tozny identity behind the unix account to the session, for use by
+// shell profiles, audit scripts, and sudo wrappers.
+fn export_login(pamh: &module::PamHandleT, config: &config::Config, login: &login::Login
+                ) -> Result<(), PamResultCode> {
+    pam_ext::putenv(pamh, ENV_USER_ID, login.user_id.as_slice())
+    .and_then(|_| pam_ext::putenv(pamh, ENV_USER_DISPLAY, login.user_display.as_slice()))
+    .and_then(|_| pam_ext::putenv(pamh, ENV_SESSION_ID, login.session_id.as_slice()))
+    .and_then(|_| pam_ext::putenv(pamh, ENV_REALM, config.realm_key_id().as_slice()))
+}
+
+fn unexport_login(pamh: &module::PamHandleT) {
+    for name in [ENV_USER_ID, ENV_USER_DISPLAY, ENV_SESSION_ID, ENV_REALM].iter() {
+        pam_ext::unsetenv(pamh, name);
+    }
+}
+
+// The circumstances of this attempt that `authorized_users` entries can restrict.
+fn attempt(log: &Logger) -> Attempt {
+    Attempt {
+        service: log.service.as_ref().map(|s| s.as_slice()),
+        rhost:   log.rhost.as_ref().map(|h| h.as_slice()),
+    }
+}
+
+// Account management reuses the login verified in the auth phase of the same
+// transaction.  The login must still be authorized (the configuration may have
+// changed since authentication), and must not have expired.
+fn check_account(config: &config::Config, login: &login::Login, attempt: Attempt
+                 ) -> Result<(), AuthError> {
+    if let Err(denial) = config.is_authorized(login, attempt) {
+        Err(AuthError::NotAuthorized(denial))
+    }
+    else if login.expires_at < time::get_time().sec {
+        Err(AuthError::LoginExpired)
+    }
+    else {
+        Ok(())
+    }
+}
+
+// Returns the presence token that the notification was sent to.
+fn push_notification(api: &Backend,
                      config: &config::Config,
                      session_id: &protocol::SessionId,
-                     ) -> bool {
-    match presence::get_presence(&config.home_dir) {
+                     log: &Logger,
+                     ) -> Option<Presence> {
+    match presence::get_presence(&config.account, config.presence_as_user) {
         Some(presence) => {
-            user_api.push(session_id, &presence)
-            .map(|_| true)
-            .unwrap_or(false)
+            if send_push(api, session_id, &presence, log) { Some(presence) } else { None }
         },
-        None => false,
+        None => {
+            log.debug("no cached presence token; cannot send push notification");
+            None
+        }
     }
 }
 
-fn show_push(config: &config::Config, conv: &PamConv) -> Result<(), AuthError> {
+fn send_push(api: &Backend, session_id: &protocol::SessionId, presence: &Presence,
+             log: &Logger) -> bool {
+    api.push(session_id, presence)
+    .map(|_| true)
+    .unwrap_or_else(|e| {
+        log.debug(&format!("push notification failed: {:?}", e));
+        false
+    })
+}
+
+fn show_push<C: Conversation + 'static>(config: &config::Config, conv: &C
+                                        ) -> Result<Option<Prompt>, AuthError> {
     interact(config, conv, |writer| {
         let _ = writer.write_str("Check your phone for a push notification from Tozny.");
+        Ok(())
     })
 }
 
-fn show_qr(config: &config::Config, conv: &PamConv, challenge: &user::LoginChallenge
-           ) -> Result<(), AuthError> {
+fn show_qr<C: Conversation + 'static>(config: &config::Config, conv: &C, challenge: &Challenge
+                                      ) -> Result<Option<Prompt>, AuthError> {
     interact(config, conv, |writer| {
-        if config.qr {
-            show_inline_qr(challenge, writer)
-        }
-        else {
-            show_qr_url(challenge, writer)
-        }
+        try!(match config.qr_style {
+            QrStyle::Url => Ok(show_qr_url(challenge, writer)),
+            style        => show_inline_qr(challenge, style, writer),
+        });
         if config.mobile_url {
             let _ = writer.write_fmt(format_args!(
                 "\n\nIf you are on your mobile device, use this URL to invoke the Tozny app:\n{}",
-                challenge.mobile_url.to_string()));
+                challenge.mobile_url));
         }
+        Ok(())
     })
 }
 
-fn show_inline_qr(challenge: &user::LoginChallenge, writer: &mut Vec<u8>) {
-    let qr = build_qr(challenge);
+fn show_inline_qr(challenge: &Challenge, style: QrStyle, writer: &mut Vec<u8>
+                  ) -> Result<(), AuthError> {
+    let qr = try!(build_qr(challenge));
     let _ = writer.write_str("\n");
-    qr_term::output_unicode(&qr, "        ", writer);
+    match style {
+        QrStyle::Ascii => qr_term::output_ascii(&qr, "        ", writer),
+        _              => qr_term::output_unicode(&qr, "        ", writer),
+    }
     let _ = writer.write_fmt(format_args!(
         "\nScan the code above with the Tozny app. \
         Or if the code does not display correctly, open this URL:\n{}",
-        challenge.qr_url.to_string()));
+        challenge.qr_url));
+    Ok(())
 }
 
-fn show_qr_url(challenge: &user::LoginChallenge, writer: &mut Vec<u8>) {
+fn show_qr_url(challenge: &Challenge, writer: &mut Vec<u8>) {
     let _ = writer.write_fmt(format_args!(
         "Open this URL, and scan the QR code with the Tozny app:\n{}",
-        challenge.qr_url.to_string()));
+        challenge.qr_url));
 }
 
-fn interact<F>(config: &config::Config, conv: &PamConv, f: F) -> Result<(), AuthError>
-        where F: Fn(&mut Vec<u8>) {
+// With the `prompt` option the message is sent as a prompt on a separate thread,
+// so that polling can start while the application waits for the user to answer.
+fn interact<C, F>(config: &config::Config, conv: &C, f: F
+                  ) -> Result<Option<Prompt>, AuthError>
+        where C: Conversation + 'static,
+              F: Fn(&mut Vec<u8>) -> Result<(), AuthError> {
     let mut writer = Vec::new();
-    f(&mut writer);
+    try!(f(&mut writer));
     if config.prompt {
-        let _ = writer.write_str("\n\nPress Enter at any time:");
-    }
-    let mtype = if config.prompt { PAM_PROMPT_ECHO_OFF } else { PAM_TEXT_INFO };
-    conv.send(mtype, str::from_utf8(writer.as_slice()).unwrap())
-    .map_err(AuthError::PamResult)
-    .and(Ok(()))
-}
-
-fn poll_session_status(api: &user::UserApi, challenge: &user::LoginChallenge, ttl: usize
-                       ) -> Result<question::Question, AuthError> {
-    api.check_session_status(&challenge.session_id)
-        .map_err(AuthError::QuestionError)
-    .and_then(|res| {
-        match res {
-            Some(question) => Ok(question),
-            None => {
-                if ttl > 0 {
-                    sleep(Duration::seconds(1));
-                    poll_session_status(api, challenge, ttl - 1)
-                }
-                else {
-                    Err(AuthError::TimedOut)
-                }
+        let _ = writer.write_fmt(format_args!("\n\n{}", prompt::INSTRUCTIONS));
+    }
+    let msg = try!(String::from_utf8(writer).map_err(|_| AuthError::InvalidUtf8));
+    if config.prompt {
+        Ok(Some(Prompt::spawn(conv, msg)))
+    }
+    else {
+        conv.send(PAM_TEXT_INFO, &msg)
+        .map_err(AuthError::PamResult)
+        .and(Ok(None))
+    }
+}
+
+// Polls until the user approves or denies the login, or until the configured
+// timeout has passed.  An open prompt does not hold up polling, but a prompt
+// cannot be interrupted either: once the outcome is known, the module waits for
+// the user to answer the prompt before it returns.
+//
+// If a push notification goes unanswered for the `push_grace` period, the QR
+// code for the same challenge is shown, and polling continues.  While a prompt
+// is open the QR code waits until the user answers it.
+fn poll_session_status<C: Conversation + 'static>(
+        config: &config::Config, conv: &C, api: &Backend, challenge: &Challenge,
+        prompt: Option<Prompt>, pushed_to: Option<&Presence>, log: &Logger
+        ) -> Result<String, AuthError> {
+    let mut prompt = prompt;
+    let result = poll_until_done(config, conv, api, challenge, &mut prompt, pushed_to, log);
+    if let Some(prompt) = prompt {
+        if prompt.is_open() {
+            log.debug("waiting for the user to answer the prompt");
+        }
+        // Dropping the prompt waits for the answer.
+        drop(prompt);
+    }
+    result
+}
+
+fn poll_until_done<C: Conversation + 'static>(
+        config: &config::Config, conv: &C, api: &Backend, challenge: &Challenge,
+        prompt: &mut Option<Prompt>, pushed_to: Option<&Presence>, log: &Logger
+        ) -> Result<String, AuthError> {
+    let start         = time::get_time();
+    let deadline      = start + config.timeout;
+    let fallback_time = start + config.push_grace;
+    let mut backoff   = poll::Backoff::new(config.poll_interval);
+    let mut shown_qr  = pushed_to.is_none();
+    loop {
+        let status = try!(api.check_session_status(&challenge.session_id)
+                          .map_err(api_error));
+        if let Some(signed_data) = status {
+            return Ok(signed_data)
+        }
+        match prompt.as_ref().map(|p| p.try_response()) {
+            Some(Response::Line(line)) => {
+                // The user is back at the keyboard; check promptly.
+                backoff.reset();
+                let command = prompt::parse_command(&line);
+                if let Command::ShowQr = command { shown_qr = true }
+                *prompt = try!(run_command(command, config, conv, api, challenge, pushed_to,
+                                           log));
             }
+            Some(Response::Failed(code)) => return Err(AuthError::PamResult(code)),
+            Some(Response::Waiting) | None => (),
         }
-    })
+        let now = time::get_time();
+        if now >= deadline {
+            return Err(AuthError::TimedOut)
+        }
+        if !shown_qr && prompt.is_none() && now >= fallback_time {
+            log.set_method(Method::Qr);
+            log.info("no response to push notification; showing QR code");
+            shown_qr = true;
+            *prompt = try!(show_qr_fallback(config, conv, challenge));
+        }
+        let delay = cmp::min(backoff.next_delay(), deadline - now);
+        log.debug(&format!("login not approved yet; checking again in {}ms",
+                           delay.num_milliseconds()));
+        sleep(delay);
+    }
+}
+
+fn show_qr_fallback<C: Conversation + 'static>(config: &config::Config, conv: &C,
+                                               challenge: &Challenge
+                                               ) -> Result<Option<Prompt>, AuthError> {
+    let _ = conv.send(PAM_TEXT_INFO, "No response to the push notification yet.");
+    show_qr(config, conv, challenge)
+}
+
+// Returns a new prompt if the command calls for further input.  A push
+// notification can only be resent to the token that the first one went to.
+fn run_command<C: Conversation + 'static>(command: Command, config: &config::Config,
+                                          conv: &C, api: &Backend, challenge: &Challenge,
+                                          pushed_to: Option<&Presence>, log: &Logger
+                                          ) -> Result<Option<Prompt>, AuthError> {
+    match command {
+        Command::Continue   => Ok(None),
+        Command::Cancel     => Err(AuthError::Cancelled),
+        Command::ShowQr     => {
+            log.set_method(Method::Qr);
+            log.info("user asked for QR code");
+            show_qr(config, conv, challenge)
+        }
+        Command::ResendPush => {
+            let did_push = pushed_to.map_or(false, |presence| {
+                send_push(api, &challenge.session_id, presence, log)
+            });
+            log.info(if did_push { "resent push notification" }
+                     else { "could not resend push notification" });
+            interact(config, conv, |writer| {
+                let _ = writer.write_str(if did_push {
+                    "Sent another push notification."
+                } else {
+                    "Could not send a push notification."
+                });
+                Ok(())
+            })
+        }
+        Command::Unknown(ref cmd) => {
+            interact(config, conv, |writer| {
+                let _ = writer.write_fmt(format_args!("Unknown command: {}", cmd));
+                Ok(())
+            })
+        }
+    }
 }
 
 fn error_code(err: &AuthError) -> PamResultCode {
     match err {
+        &AuthError::Cancelled              => PAM_AUTH_ERR,
         &AuthError::ConfigError(_)         => PAM_AUTHINFO_UNAVAIL,
-        &AuthError::NotAuthorized          => PAM_PERM_DENIED,
+        &AuthError::InvalidArguments       => PAM_SERVICE_ERR,
+        &AuthError::InvalidUtf8            => PAM_SERVICE_ERR,
+        &AuthError::LoginExpired           => PAM_ACCT_EXPIRED,
+        &AuthError::NoInvokingUser         => PAM_USER_UNKNOWN,
+        &AuthError::NoLogin                => PAM_PERM_DENIED,
+        &AuthError::NotAuthorized(_)       => PAM_PERM_DENIED,
         &AuthError::PamResult(code)        => code,
+        &AuthError::QrError(_)             => PAM_SERVICE_ERR,
+        &AuthError::TargetNotAllowed(_)    => PAM_PERM_DENIED,
         &AuthError::TimedOut               => PAM_AUTHINFO_UNAVAIL,
         &AuthError::QuestionError(ref err) => match err {
             &question::QuestionError::InvalidSignature => PAM_AUTH_ERR,
             _                                          => PAM_SERVICE_ERR,
         },
+        &AuthError::Unavailable(_)         => PAM_SERVICE_ERR,
+    }
+}
+
+// `pam_sm_acct_mgmt` may only return a subset of PAM result codes.
+fn account_error_code(err: &AuthError) -> PamResultCode {
+    match err {
+        &AuthError::LoginExpired                         => PAM_ACCT_EXPIRED,
+        &AuthError::NotAuthorized(Denial::Expired(_, _)) => PAM_ACCT_EXPIRED,
+        &AuthError::PamResult(code)                      => code,
+        _                                                => PAM_PERM_DENIED,
+    }
+}
+
+// Sessions that were not authenticated with Tozny are none of our business.
+fn session_error_code(err: &AuthError) -> PamResultCode {
+    match err {
+        &AuthError::NoLogin => PAM_IGNORE,
+        _                   => PAM_SESSION_ERR,
     }
 }
 
 impl fmt::Display for AuthError {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         match self {
+            &AuthError::Cancelled            => {
+                f.write_str("Authentication cancelled.")
+            }
             &AuthError::ConfigError(ref err) => err.fmt(f),
-            &AuthError::NotAuthorized        => {
+            &AuthError::InvalidArguments     => {
+                f.write_str("Invalid module arguments.")
+            }
+            &AuthError::InvalidUtf8          => {
+                f.write_str("Message is not valid UTF-8.")
+            }
+            &AuthError::LoginExpired         => {
+                f.write_str("Your Tozny login has expired.")
+            }
+            &AuthError::NoInvokingUser       => {
+                f.write_str("Could not determine who is running this command.")
+            }
+            &AuthError::NoLogin              => {
+                f.write_str("No Tozny login was verified for this session.")
+            }
+            &AuthError::NotAuthorized(Denial::NotListed) => {
                 f.write_str("You are not authorized to access this account.")
             }
+            &AuthError::NotAuthorized(ref denial) => {
+                f.write_fmt(format_args!("You are not authorized to access this account: {}.",
+                                         denial))
+            }
             &AuthError::PamResult(_)         => Ok(()),
+            &AuthError::QrError(ref err)     => {
+                f.write_fmt(format_args!("Could not build QR code: {:?}", err))
+            }
+            &AuthError::TargetNotAllowed(ref target) => {
+                f.write_fmt(format_args!("You are not allowed to become {}.", target))
+            }
             &AuthError::TimedOut             => {
                 f.write_str("Timed out waiting for user to authenticate.")
             }
             &AuthError::QuestionError(ref err) => err.fmt(f),
+            &AuthError::Unavailable(ref err)   => {
+                f.write_fmt(format_args!("Could not reach Tozny: {}", err))
+            }
         }
     }
 }
@@ -229,15 +854,304 @@ fn show_err(pamh: &module::PamHandleT, err: &AuthError) {
     }
 }
 
-fn build_qr(challenge: &user::LoginChallenge) -> qrcode::QrCode {
-    qrcode::QrCode::new(challenge.mobile_url.to_string().as_bytes()).unwrap()
+fn build_qr(challenge: &Challenge) -> Result<qrcode::QrCode, AuthError> {
+    qrcode::QrCode::new(challenge.mobile_url.as_bytes())
+    .map_err(AuthError::QrError)
 }
 
-unsafe fn translate_args(argc: c_int, argv: *const *const c_char) -> Vec<String> {
-    let v = Vec::<*const c_char>::from_raw_buf(argv, num::cast(argc).unwrap());
-    v.into_iter().filter_map(|arg| {
+unsafe fn translate_args(argc: c_int, argv: *const *const c_char
+                         ) -> Result<Vec<String>, AuthError> {
+    let len = try!(num::cast(argc).ok_or(AuthError::InvalidArguments));
+    let v = Vec::<*const c_char>::from_raw_buf(argv, len);
+    v.into_iter().map(|arg| {
         let bytes = ffi::CStr::from_ptr(arg).to_bytes();
-        String::from_utf8(bytes.to_vec()).ok()
+        String::from_utf8(bytes.to_vec()).map_err(|_| AuthError::InvalidArguments)
     })
     .collect()
 }
+
+// An implementation of one of the exported PAM functions.
+type Handler = fn(&module::PamHandleT, PamFlag, &[String]) -> PamResultCode;
+
+// The arguments to an exported function, to be moved to the thread that runs
+// the implementation.  libpam keeps them alive until the function returns.
+struct RawCall {
+    pamh: *const module::PamHandleT,
+    argc: c_int,
+    argv: *const *const c_char,
+}
+unsafe impl Send for RawCall {}
+
+// Runs `handler` on a separate thread, and reports `PAM_SERVICE_ERR` if it
+// panics, instead of letting the panic unwind into the application.
+fn guarded(name: &'static str, handler: Handler, pamh: &module::PamHandleT, flags: PamFlag,
+           argc: c_int, argv: *const *const c_char) -> PamResultCode {
+    let call = RawCall { pamh: pamh, argc: argc, argv: argv };
+    let result = thread::Builder::new().name(name.to_string()).spawn(move || {
+        let pamh = unsafe { &*call.pamh };
+        match unsafe { translate_args(call.argc, call.argv) } {
+            Ok(args) => handler(pamh, flags, &args),
+            Err(e)   => {
+                pam_ext::syslog(pamh, pam_ext::LOG_ERR, &format!("{}: {}", name, e));
+                error_code(&e)
+            }
+        }
+    })
+    .map_err(|e| format!("could not start thread: {}", e))
+    .and_then(|thread| {
+        thread.join().map_err(|payload| {
+            match payload.downcast_ref::<&'static str>() {
+                Some(msg) => msg.to_string(),
+                None      => match payload.downcast_ref::<String>() {
+                    Some(msg) => msg.clone(),
+                    None      => "unknown cause".to_string(),
+                },
+            }
+        })
+    });
+    result.unwrap_or_else(|cause| {
+        pam_ext::syslog(pamh, pam_ext::LOG_ERR, &format!("{} panicked: {}", name, cause));
+        PAM_SERVICE_ERR
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use libc::{getgid, getuid};
+    use pam::constants::*;
+    use std::old_io::{TempDir};
+    use std::old_path::posix::{Path};
+    use std::time::Duration;
+    use tozny_auth::protocol::{Newtype, Presence};
+    use tozny_auth::question::{QuestionError};
+
+    use authorized::{Denial};
+    use backend::{FakeBackend};
+    use config::{Config, ConfigError, OnUnavailable, QrStyle};
+    use conv::{FakeConversation};
+    use logger::{Logger};
+    use passwd::{Passwd};
+    use presence;
+    use super::{account_error_code, authenticate, check_target, error_code, is_unavailable,
+                unavailable_code, AuthError};
+
+    // The fake backend does not sign logins; this is what it "signs".
+    const ALICE: &'static str =
+        r#"{"user_id":"sid_alice","user_display":"alice","session_id":"sess_1","expires_at":4102444800}"#;
+
+    fn config(authorized_users: &[&str]) -> Config {
+        let account = Passwd {
+            name:   "alice".to_string(),
+            uid:    1000,
+            gid:    1000,
+            home:   Path::new("/nonexistent"),
+            shell:  Path::new("/bin/sh"),
+            groups: vec![1000],
+        };
+        let mut config = Config::for_test("sid_realm", authorized_users, account);
+        config.presence      = false;
+        config.qr_style      = QrStyle::Url;
+        config.poll_interval = Duration::milliseconds(1);
+        config.timeout       = Duration::seconds(2);
+        config
+    }
+
+    // Gives the account a home directory with a cached presence token, so that
+    // push notifications are sent.  The directory is removed when dropped.
+    fn with_presence(config: &mut Config) -> TempDir {
+        let home = TempDir::new("toznyauth_pam").ok().expect("cannot create home directory");
+        config.presence     = true;
+        config.account.uid  = unsafe { getuid() };
+        config.account.gid  = unsafe { getgid() };
+        config.account.home = home.path().clone();
+        presence::save_presence(&config.account, false, &Presence::new("cached".to_string()))
+            .ok().expect("cannot save presence token");
+        home
+    }
+
+    fn expect_err<T>(result: Result<T, AuthError>) -> AuthError {
+        match result {
+            Ok(_)    => panic!("expected authentication to fail"),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn approves_after_polling() {
+        let config = config(&["sid_alice"]);
+        let api    = FakeBackend::new("sess_1");
+        let conv   = FakeConversation::new(&[]);
+        api.approve_after(3, ALICE);
+        let login = authenticate(&config, &api, &conv, &Logger::discard("alice"))
+            .ok().expect("expected authentication to succeed");
+        assert_eq!(login.user_id.as_slice(), "sid_alice");
+        let messages = conv.messages();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].0, PAM_TEXT_INFO);
+        assert!(messages[0].1.contains("https://fake.invalid/qr/sess_1"));
+    }
+
+    #[test]
+    fn rejects_unauthorized_user() {
+        let config = config(&["sid_bob"]);
+        let api    = FakeBackend::new("sess_1");
+        api.approve_after(0, ALICE);
+        let err = expect_err(authenticate(&config, &api, &FakeConversation::new(&[]),
+                                          &Logger::discard("alice")));
+        match err {
+            AuthError::NotAuthorized(Denial::NotListed) => (),
+            ref other => panic!("unexpected error: {}", other),
+        }
+        assert_eq!(error_code(&err), PAM_PERM_DENIED);
+    }
+
+    #[test]
+    fn rejects_bad_signature() {
+        let config = config(&["sid_alice"]);
+        let api    = FakeBackend::new("sess_1");
+        api.approve_after(0, "not a signed login");
+        let err = expect_err(authenticate(&config, &api, &FakeConversation::new(&[]),
+                                          &Logger::discard("alice")));
+        assert_eq!(error_code(&err), PAM_AUTH_ERR);
+    }
+
+    #[test]
+    fn account_phase_reports_expired_entries() {
+        let expired = AuthError::NotAuthorized(Denial::Expired("alice".to_string(),
+                                                               "2001-01-01".to_string()));
+        assert_eq!(account_error_code(&expired), PAM_ACCT_EXPIRED);
+        assert_eq!(account_error_code(&AuthError::LoginExpired), PAM_ACCT_EXPIRED);
+        assert_eq!(account_error_code(&AuthError::NotAuthorized(Denial::NotListed)),
+                   PAM_PERM_DENIED);
+    }
+
+    #[test]
+    fn only_own_account_without_allowed_targets() {
+        let config = config(&["sid_alice"]);
+        assert!(check_target(&config, "alice").is_ok());
+        let err = expect_err(check_target(&config, "root"));
+        match err {
+            AuthError::TargetNotAllowed(ref target) if *target == "root" => (),
+            ref other => panic!("unexpected error: {}", other),
+        }
+        assert_eq!(error_code(&err), PAM_PERM_DENIED);
+    }
+
+    #[test]
+    fn on_unavailable_decides_result() {
+        assert_eq!(unavailable_code(OnUnavailable::Deny), PAM_AUTH_ERR);
+        assert_eq!(unavailable_code(OnUnavailable::Ignore), PAM_IGNORE);
+        assert_eq!(unavailable_code(OnUnavailable::Success), PAM_SUCCESS);
+    }
+
+    #[test]
+    fn only_unreachable_api_and_missing_config_are_unavailable() {
+        assert!(is_unavailable(&AuthError::ConfigError(ConfigError::NoHomeDir)));
+        assert!(is_unavailable(&AuthError::ConfigError(
+            ConfigError::MissingAuthFile(Path::new("/nonexistent/authorized.toml")))));
+        assert!(!is_unavailable(&AuthError::ConfigError(ConfigError::ParseError)));
+        assert!(!is_unavailable(&AuthError::TimedOut));
+        assert!(!is_unavailable(&AuthError::Cancelled));
+        assert!(!is_unavailable(&AuthError::NotAuthorized(Denial::NotListed)));
+    }
+
+    #[test]
+    fn times_out_when_never_approved() {
+        let mut config = config(&["sid_alice"]);
+        config.timeout = Duration::milliseconds(20);
+        let api = FakeBackend::new("sess_1");
+        let err = expect_err(authenticate(&config, &api, &FakeConversation::new(&[]),
+                                          &Logger::discard("alice")));
+        match err {
+            AuthError::TimedOut => (),
+            other               => panic!("unexpected error: {}", other),
+        }
+    }
+
+    #[test]
+    fn cancels_from_prompt() {
+        let mut config = config(&["sid_alice"]);
+        config.prompt = true;
+        let api  = FakeBackend::new("sess_1");
+        let conv = FakeConversation::new(&["q"]);
+        let err = expect_err(authenticate(&config, &api, &conv, &Logger::discard("alice")));
+        match err {
+            AuthError::Cancelled => (),
+            other                => panic!("unexpected error: {}", other),
+        }
+        assert_eq!(conv.messages()[0].0, PAM_PROMPT_ECHO_OFF);
+    }
+
+    #[test]
+    fn falls_back_to_qr_after_push_grace() {
+        let mut config = config(&["sid_alice"]);
+        let _home = with_presence(&mut config);
+        config.push_grace = Duration::milliseconds(5);
+        config.timeout    = Duration::milliseconds(100);
+        let api  = FakeBackend::new("sess_1");
+        let conv = FakeConversation::new(&[]);
+        let err = expect_err(authenticate(&config, &api, &conv, &Logger::discard("alice")));
+        match err {
+            AuthError::TimedOut => (),
+            other               => panic!("unexpected error: {}", other),
+        }
+        assert_eq!(api.pushes(), vec!["cached".to_string()]);
+        let messages = conv.messages();
+        assert_eq!(messages.len(), 3);
+        assert!(messages[0].1.contains("Check your phone"));
+        assert_eq!(messages[1].1, "No response to the push notification yet.");
+        assert!(messages[2].1.contains("https://fake.invalid/qr/sess_1"));
+    }
+
+    #[test]
+    fn resends_push_from_prompt() {
+        let mut config = config(&["sid_alice"]);
+        let _home = with_presence(&mut config);
+        config.prompt = true;
+        let api  = FakeBackend::new("sess_1");
+        let conv = FakeConversation::new(&["r", "q"]);
+        match expect_err(authenticate(&config, &api, &conv, &Logger::discard("alice"))) {
+            AuthError::Cancelled => (),
+            other                => panic!("unexpected error: {}", other),
+        }
+        // The challenge's token replaces the cached one, but the resend goes
+        // to the token that the first push went to.
+        assert_eq!(api.pushes(), vec!["cached".to_string(), "cached".to_string()]);
+        let messages = conv.messages();
+        assert_eq!(messages.len(), 2);
+        assert!(messages[1].1.starts_with("Sent another push notification."));
+    }
+
+    #[test]
+    fn shows_qr_from_prompt() {
+        let mut config = config(&["sid_alice"]);
+        let _home = with_presence(&mut config);
+        config.prompt = true;
+        let api  = FakeBackend::new("sess_1");
+        let conv = FakeConversation::new(&["qr", "q"]);
+        match expect_err(authenticate(&config, &api, &conv, &Logger::discard("alice"))) {
+            AuthError::Cancelled => (),
+            other                => panic!("unexpected error: {}", other),
+        }
+        let messages = conv.messages();
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].1.contains("Check your phone"));
+        assert!(messages[1].1.contains("https://fake.invalid/qr/sess_1"));
+    }
+
+    #[test]
+    fn fails_on_status_error_while_polling() {
+        let config = config(&["sid_alice"]);
+        let api    = FakeBackend::new("sess_1");
+        api.leave_pending(3);
+        api.fail_with(QuestionError::InvalidSignature);
+        let err = expect_err(authenticate(&config, &api, &FakeConversation::new(&[]),
+                                          &Logger::discard("alice")));
+        match err {
+            AuthError::QuestionError(QuestionError::InvalidSignature) => (),
+            ref other => panic!("unexpected error: {}", other),
+        }
+        assert!(!is_unavailable(&err));
+        assert_eq!(error_code(&err), PAM_AUTH_ERR);
+    }
+}
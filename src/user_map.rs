@@ -0,0 +1,203 @@
+// Root-owned mapping of Tozny identities to the unix accounts they may log in
+// to, for sites that would rather manage access in one place than in each
+// account's `authorized_users`.  Each entry names one Tozny user id, and may
+// limit it to some PAM services and hosts:
+//
+//     [[user]]
+//     id       = "sid_123456789"
+//     accounts = ["alice", "deploy"]
+//     services = ["sshd"]
+//     hosts    = ["web1.example.com"]
+//
+// An identity may have several entries, and an account may appear in the
+// entries of several identities.  The file is read on every attempt, so
+// changes apply to the next login.
+
+use libc::{c_char, c_int, size_t};
+use std::ffi::{CStr};
+use std::old_io::{File, Reader};
+use std::old_io::fs::{PathExtensions};
+use std::old_path::posix::{Path};
+use toml;
+
+use config;
+use config::{ConfigError};
+
+extern {
+    fn gethostname(name: *mut c_char, len: size_t) -> c_int;
+}
+
+#[derive(PartialEq, Debug)]
+pub struct UserMap {
+    entries: Vec<Entry>,
+}
+
+#[derive(PartialEq, Debug)]
+struct Entry {
+    id:       String,
+    accounts: Vec<String>,
+    services: Option<Vec<String>>,
+    hosts:    Option<Vec<String>>,
+}
+
+impl UserMap {
+    pub fn empty() -> UserMap {
+        UserMap { entries: Vec::new() }
+    }
+
+    /// Reads the mapping at `path`, which need not exist.  The file grants
+    /// access to any account, so it must be owned by root and not writable by
+    /// anyone else, whatever `strict_modes` says about per-user files.
+    pub fn read(path: &Path) -> Result<UserMap, ConfigError> {
+        if !path.exists() {
+            return Ok(UserMap::empty())
+        }
+        try!(config::check_root_owned(path));
+        let input = try!(File::open(path).read_to_string().map_err(ConfigError::ErrorReading));
+        UserMap::parse(&input)
+    }
+
+    fn parse(input: &str) -> Result<UserMap, ConfigError> {
+        let table = try!(toml::Parser::new(input).parse().ok_or(ConfigError::ParseError));
+        let entries = match table.get("user") {
+            Some(users) => try!(config::as_slice(users)),
+            None        => &[][..],
+        };
+        entries.iter().map(Entry::from_value).collect::<Result<Vec<Entry>, ConfigError>>()
+        .map(|entries| UserMap { entries: entries })
+    }
+
+    /// A mapping given inline, for tests that do not read any files.
+    #[cfg(test)]
+    pub fn for_test(input: &str) -> UserMap {
+        UserMap::parse(input).ok().expect("invalid user mapping")
+    }
+
+    /// Whether any identity is mapped to `account`, on any service or host.
+    pub fn covers(&self, account: &str) -> bool {
+        self.entries.iter().any(|e| e.accounts.iter().any(|a| a == account))
+    }
+
+    /// Whether the Tozny user `user_id` may log in to `account` through
+    /// `service` on this host.
+    pub fn allows(&self, user_id: &str, account: &str, service: Option<&str>) -> bool {
+        let host = hostname();
+        self.allows_on(user_id, account, service, host.as_ref().map(|h| h.as_slice()))
+    }
+
+    fn allows_on(&self, user_id: &str, account: &str, service: Option<&str>, host: Option<&str>
+                 ) -> bool {
+        self.entries.iter().any(|e| {
+            e.id == user_id &&
+            e.accounts.iter().any(|a| a == account) &&
+            config::matches(&e.services, service, |s, a| s == a) &&
+            config::matches(&e.hosts, host, |h, listed| host_matches(h, listed))
+        })
+    }
+}
+
+impl Entry {
+    fn from_value(value: &toml::Value) -> Result<Entry, ConfigError> {
+        let table = try!(value.as_table().ok_or_else(|| {
+            ConfigError::TypeError("Table", value.type_str())
+        }));
+        let id       = try!(config::get(table, "id").and_then(config::as_str));
+        let accounts = try!(config::get(table, "accounts").and_then(config::as_names));
+        let services = try!(config::get_opt(table, "services", config::as_names));
+        let hosts    = try!(config::get_opt(table, "hosts", config::as_names));
+        Ok(Entry {
+            id:       id.to_string(),
+            accounts: accounts,
+            services: services,
+            hosts:    hosts,
+        })
+    }
+}
+
+// Hosts may be listed by their full name or by the part before the first dot.
+fn host_matches(host: &str, listed: &str) -> bool {
+    host == listed || host.split('.').next() == Some(listed)
+}
+
+fn hostname() -> Option<String> {
+    let mut buf = [0 as c_char; 256];
+    if unsafe { gethostname(buf.as_mut_ptr(), buf.len() as size_t) } != 0 {
+        return None
+    }
+    let bytes = unsafe { CStr::from_ptr(buf.as_ptr()) }.to_bytes();
+    String::from_utf8(bytes.to_vec()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{host_matches, UserMap};
+
+    const USERS: &'static str = r#"
+        [[user]]
+        id       = "sid_alice"
+        accounts = ["alice", "deploy"]
+
+        [[user]]
+        id       = "sid_bob"
+        accounts = ["bob", "deploy"]
+        services = ["sshd"]
+        hosts    = ["web1"]
+
+        [[user]]
+        id       = "sid_bob"
+        accounts = ["backup"]
+        hosts    = ["db1.example.com"]
+    "#;
+
+    #[test]
+    fn allows_mapped_identities() {
+        let map = UserMap::for_test(USERS);
+        for &(user_id, account, service, host, allowed) in [
+            ("sid_alice", "alice",  Some("sshd"), Some("web1"),             true),
+            ("sid_alice", "deploy", None,         None,                     true),
+            ("sid_alice", "bob",    Some("sshd"), Some("web1"),             false),
+            ("sid_bob",   "deploy", Some("sshd"), Some("web1.example.com"), true),
+            ("sid_bob",   "deploy", Some("sudo"), Some("web1"),             false),
+            ("sid_bob",   "deploy", None,         Some("web1"),             false),
+            ("sid_bob",   "deploy", Some("sshd"), Some("web2"),             false),
+            ("sid_bob",   "deploy", Some("sshd"), None,                     false),
+            ("sid_bob",   "backup", Some("sudo"), Some("db1.example.com"),  true),
+            ("sid_bob",   "backup", Some("sudo"), Some("db1"),              false),
+            ("sid_carol", "alice",  Some("sshd"), Some("web1"),             false),
+        ].iter() {
+            assert!(map.allows_on(user_id, account, service, host) == allowed,
+                    "{} as {} through {:?} on {:?}", user_id, account, service, host);
+        }
+    }
+
+    #[test]
+    fn covers_mapped_accounts() {
+        let map = UserMap::for_test(USERS);
+        assert!(map.covers("alice"));
+        assert!(map.covers("deploy"));
+        assert!(map.covers("backup"));
+        assert!(!map.covers("carol"));
+        assert!(!UserMap::empty().covers("alice"));
+    }
+
+    #[test]
+    fn matches_short_and_full_host_names() {
+        assert!(host_matches("web1.example.com", "web1"));
+        assert!(host_matches("web1.example.com", "web1.example.com"));
+        assert!(host_matches("web1", "web1"));
+        assert!(!host_matches("web1", "web1.example.com"));
+        assert!(!host_matches("web10.example.com", "web1"));
+    }
+
+    #[test]
+    fn rejects_invalid_entries() {
+        for entry in [r#"accounts = ["alice"]"#,
+                      r#"id = "sid_alice""#,
+                      r#"id = "sid_alice"
+                         accounts = "alice"
+                         hosts = 3"#].iter() {
+            let input = format!("[[user]]\n{}", entry);
+            assert!(UserMap::parse(&input).is_err(), "accepted {}", entry);
+        }
+    }
+}
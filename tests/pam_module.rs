@@ -0,0 +1,405 @@
+// End-to-end tests of the built module.  The module is loaded through libpam,
+// with pam_wrapper supplying the service files and nss_wrapper supplying the
+// test accounts, and it talks to `tozny_mock_api` instead of the real Tozny
+// API.
+//
+// These tests need the wrappers preloaded, so run them with
+// `tests/pam_wrapper.sh`.  Under plain `cargo test` they do nothing.
+
+#![feature(env)]
+#![feature(libc)]
+#![feature(old_io)]
+#![feature(old_path)]
+#![feature(std_misc)]
+
+extern crate libc;
+
+use libc::{c_char, c_int, c_void, gid_t, size_t, uid_t};
+use std::collections::{VecDeque};
+use std::env;
+use std::ffi::{CStr, CString};
+use std::mem;
+use std::old_io::{fs, File, USER_RWX};
+use std::old_io::fs::{PathExtensions};
+use std::old_io::net::tcp::{TcpStream};
+use std::old_io::process::{Command, Process};
+use std::old_io::timer::{sleep};
+use std::old_path::posix::{Path};
+use std::ptr;
+use std::sync::{Mutex, Once, ONCE_INIT};
+use std::time::Duration;
+
+const PAM_SUCCESS:          c_int = 0;
+const PAM_SERVICE_ERR:      c_int = 3;
+const PAM_PERM_DENIED:      c_int = 6;
+const PAM_AUTH_ERR:         c_int = 7;
+const PAM_AUTHINFO_UNAVAIL: c_int = 9;
+const PAM_CONV_ERR:         c_int = 19;
+
+const PAM_USER: c_int = 2;
+
+const PAM_PROMPT_ECHO_OFF: c_int = 1;
+const PAM_PROMPT_ECHO_ON:  c_int = 2;
+const PAM_ERROR_MSG:       c_int = 3;
+const PAM_TEXT_INFO:       c_int = 4;
+
+// One account per test, so that tests can run in parallel without sharing a
+// home directory.
+const USERS: &'static [&'static str] = &[
+    "tozny_approve", "tozny_deny", "tozny_never", "tozny_malformed", "tozny_unauthorized",
+    "tozny_no_config", "tozny_unavailable_success", "tozny_unavailable_deny",
+];
+
+const MOCK_USER_ID: &'static str = "sid_mock_user";
+const MOCK_REALM:   &'static str = "sid_mock_realm";
+
+enum PamHandle {}
+
+#[repr(C)]
+struct PamMessage {
+    msg_style: c_int,
+    msg:       *const c_char,
+}
+
+#[repr(C)]
+struct PamResponse {
+    resp:         *mut c_char,
+    resp_retcode: c_int,
+}
+
+#[repr(C)]
+struct PamConv {
+    conv:        extern fn(c_int, *const *const PamMessage, *mut *mut PamResponse,
+                           *mut c_void) -> c_int,
+    appdata_ptr: *mut c_void,
+}
+
+#[link(name = "pam")]
+extern {
+    fn pam_start(service: *const c_char, user: *const c_char, conv: *const PamConv,
+                 pamh: *mut *mut PamHandle) -> c_int;
+    fn pam_end(pamh: *mut PamHandle, status: c_int) -> c_int;
+    fn pam_authenticate(pamh: *mut PamHandle, flags: c_int) -> c_int;
+    fn pam_acct_mgmt(pamh: *mut PamHandle, flags: c_int) -> c_int;
+    fn pam_get_item(pamh: *const PamHandle, item_type: c_int, item: *mut *const c_void) -> c_int;
+    fn pam_getenv(pamh: *mut PamHandle, name: *const c_char) -> *const c_char;
+}
+
+extern {
+    fn getuid() -> uid_t;
+    fn getgid() -> gid_t;
+}
+
+/// Everything the module sent through the conversation, and scripted answers
+/// to its prompts.  Prompts arrive on the module's own thread.
+struct Transcript {
+    answers:  Mutex<VecDeque<String>>,
+    messages: Mutex<Vec<(c_int, String)>>,
+}
+
+impl Transcript {
+    fn new(answers: &[&str]) -> Transcript {
+        Transcript {
+            answers:  Mutex::new(answers.iter().map(|a| a.to_string()).collect()),
+            messages: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn messages(&self) -> Vec<(c_int, String)> {
+        self.messages.lock().unwrap().clone()
+    }
+
+    fn contains(&self, style: c_int, text: &str) -> bool {
+        self.messages().iter().any(|&(s, ref m)| s == style && m.contains(text))
+    }
+}
+
+extern fn converse(num_msg: c_int, msg: *const *const PamMessage, resp: *mut *mut PamResponse,
+                   appdata_ptr: *mut c_void) -> c_int {
+    let transcript = unsafe { &*(appdata_ptr as *const Transcript) };
+    let n = num_msg as usize;
+    unsafe {
+        let responses = libc::calloc(n as size_t, mem::size_of::<PamResponse>() as size_t)
+                        as *mut PamResponse;
+        if responses.is_null() {
+            return PAM_CONV_ERR
+        }
+        for i in 0..n {
+            let m = &**msg.offset(i as isize);
+            let text = String::from_utf8_lossy(CStr::from_ptr(m.msg).to_bytes()).into_owned();
+            transcript.messages.lock().unwrap().push((m.msg_style, text));
+            if m.msg_style == PAM_PROMPT_ECHO_OFF || m.msg_style == PAM_PROMPT_ECHO_ON {
+                let answer = transcript.answers.lock().unwrap().pop_front()
+                             .unwrap_or(String::new());
+                (*responses.offset(i as isize)).resp = malloc_string(&answer);
+            }
+        }
+        *resp = responses;
+    }
+    PAM_SUCCESS
+}
+
+// libpam frees responses with `free`.
+unsafe fn malloc_string(s: &str) -> *mut c_char {
+    let bytes = s.as_bytes();
+    let p = libc::malloc((bytes.len() + 1) as size_t) as *mut u8;
+    ptr::copy(p, bytes.as_ptr(), bytes.len());
+    *p.offset(bytes.len() as isize) = 0;
+    p as *mut c_char
+}
+
+/// A PAM transaction for one service and user.
+struct Transaction {
+    pamh:       *mut PamHandle,
+    // libpam holds pointers to these until `pam_end`.
+    _conv:      Box<PamConv>,
+    transcript: Box<Transcript>,
+}
+
+impl Transaction {
+    fn start(service: &str, user: &str, answers: &[&str]) -> Transaction {
+        let transcript = Box::new(Transcript::new(answers));
+        let conv = Box::new(PamConv {
+            conv:        converse,
+            appdata_ptr: &*transcript as *const Transcript as *mut c_void,
+        });
+        let c_service = CString::new(service).unwrap();
+        let c_user    = CString::new(user).unwrap();
+        let mut pamh  = ptr::null_mut();
+        let code = unsafe { pam_start(c_service.as_ptr(), c_user.as_ptr(), &*conv, &mut pamh) };
+        assert_eq!(code, PAM_SUCCESS);
+        Transaction { pamh: pamh, _conv: conv, transcript: transcript }
+    }
+
+    fn authenticate(&self) -> c_int {
+        unsafe { pam_authenticate(self.pamh, 0) }
+    }
+
+    fn acct_mgmt(&self) -> c_int {
+        unsafe { pam_acct_mgmt(self.pamh, 0) }
+    }
+
+    fn getenv(&self, name: &str) -> Option<String> {
+        let c_name = CString::new(name).unwrap();
+        let value = unsafe { pam_getenv(self.pamh, c_name.as_ptr()) };
+        if value.is_null() { return None }
+        Some(String::from_utf8_lossy(unsafe { CStr::from_ptr(value) }.to_bytes()).into_owned())
+    }
+
+    fn get_str_item(&self, item_type: c_int) -> Option<String> {
+        let mut item = ptr::null();
+        let code = unsafe { pam_get_item(self.pamh, item_type, &mut item) };
+        if code != PAM_SUCCESS || item.is_null() { return None }
+        let bytes = unsafe { CStr::from_ptr(item as *const c_char) }.to_bytes();
+        Some(String::from_utf8_lossy(bytes).into_owned())
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        unsafe { pam_end(self.pamh, PAM_SUCCESS) };
+    }
+}
+
+/// A running `tozny_mock_api`, which is killed when dropped.
+struct MockApi {
+    process: Process,
+    port:    u16,
+}
+
+impl MockApi {
+    fn start(port: u16, outcome: &str, polls: usize) -> MockApi {
+        let process = Command::new(required_env("TOZNY_MOCK_API"))
+            .arg("--port").arg(port.to_string())
+            .arg("--outcome").arg(outcome)
+            .arg("--polls").arg(polls.to_string())
+            .arg("--realm-key-id").arg(MOCK_REALM)
+            .arg("--user-id").arg(MOCK_USER_ID)
+            .spawn()
+            .ok().expect("could not start tozny_mock_api");
+        for _ in 0..50 {
+            if TcpStream::connect(("127.0.0.1", port)).is_ok() {
+                return MockApi { process: process, port: port }
+            }
+            sleep(Duration::milliseconds(100));
+        }
+        panic!("tozny_mock_api did not start listening on port {}", port)
+    }
+}
+
+impl Drop for MockApi {
+    fn drop(&mut self) {
+        let _ = self.process.signal_kill();
+        let _ = self.process.wait();
+    }
+}
+
+// Test files live in this directory, which `tests/pam_wrapper.sh` sets up.
+fn test_dir() -> Option<Path> {
+    env::var("TOZNY_PAM_TEST_DIR").ok().map(Path::new)
+}
+
+fn required_env(name: &str) -> String {
+    env::var(name).ok().expect(&format!("{} is not set; run tests/pam_wrapper.sh", name))
+}
+
+static SETUP: Once = ONCE_INIT;
+
+// Writes the nss_wrapper account files, before the first account lookup.
+// Every test account has the uid of the test process, so that the files the
+// tests create pass the module's ownership checks.
+fn setup(dir: &Path) {
+    SETUP.call_once(|| {
+        let (uid, gid) = unsafe { (getuid(), getgid()) };
+        let mut passwd = String::new();
+        for user in USERS.iter() {
+            let home = dir.join("home").join(*user);
+            fs::mkdir_recursive(&home, USER_RWX).unwrap();
+            passwd.push_str(&format!("{}:x:{}:{}:Tozny test:{}:/bin/sh\n",
+                                     user, uid, gid, home.display()));
+        }
+        write_file(&dir.join("passwd"), &passwd);
+        write_file(&dir.join("group"), &format!("tozny:x:{}:\n", gid));
+    });
+}
+
+fn write_file(path: &Path, contents: &str) {
+    File::create(path).write_str(contents).unwrap();
+}
+
+fn home(dir: &Path, user: &str) -> Path {
+    dir.join("home").join(user)
+}
+
+// Writes a system-wide configuration pointing at `api`, a per-user file that
+// authorizes `authorized_users`, and a service file for the test.  The service
+// has the same name as the account.
+fn configure(dir: &Path, user: &str, api: &MockApi, authorized_users: Option<&[&str]>,
+             options: &str) {
+    setup(dir);
+    let system = dir.join(format!("{}.toml", user));
+    write_file(&system, &format!(
+        "api_url = \"http://127.0.0.1:{}/index.php\"\nrealm_key_id = \"{}\"\n",
+        api.port, MOCK_REALM));
+    if let Some(users) = authorized_users {
+        let config_dir = home(dir, user).join(".config").join("tozny");
+        fs::mkdir_recursive(&config_dir, USER_RWX).unwrap();
+        let list: Vec<String> = users.iter().map(|u| format!("\"{}\"", u)).collect();
+        write_file(&config_dir.join("authorized.toml"),
+                   &format!("authorized_users = [{}]\n", list.connect(", ")));
+    }
+    // The central mapping does not exist, so that the real one is not read.
+    let users_file = dir.join("users.toml");
+    let module = required_env("TOZNY_PAM_MODULE");
+    write_file(&dir.join("services").join(user), &format!(
+        "auth    required {} config={} users_file={} poll_interval=1 {}\n\
+         account required {} config={} users_file={}\n",
+        module, system.display(), users_file.display(), options,
+        module, system.display(), users_file.display()));
+}
+
+#[test]
+fn approves_and_exports_login() {
+    let dir = match test_dir() { Some(dir) => dir, None => return };
+    let user = "tozny_approve";
+    let api = MockApi::start(18201, "approve", 1);
+    configure(&dir, user, &api, Some(&[MOCK_USER_ID]), "");
+    let pam = Transaction::start(user, user, &[]);
+
+    assert_eq!(pam.authenticate(), PAM_SUCCESS);
+    assert!(pam.transcript.contains(PAM_TEXT_INFO,
+                                    &format!("http://127.0.0.1:{}/qr/", api.port)));
+    assert!(pam.transcript.contains(PAM_TEXT_INFO, "Authenticated as Mock User"));
+    assert_eq!(pam.get_str_item(PAM_USER), Some(user.to_string()));
+    assert_eq!(pam.getenv("TOZNY_USER_ID"), Some(MOCK_USER_ID.to_string()));
+    assert_eq!(pam.getenv("TOZNY_REALM"), Some(MOCK_REALM.to_string()));
+    assert!(home(&dir, user).join(".cache").join("toznyauth_pam_presence").is_file());
+    assert_eq!(pam.acct_mgmt(), PAM_SUCCESS);
+}
+
+#[test]
+fn rejects_denied_login() {
+    let dir = match test_dir() { Some(dir) => dir, None => return };
+    let user = "tozny_deny";
+    let api = MockApi::start(18202, "deny", 0);
+    configure(&dir, user, &api, Some(&[MOCK_USER_ID]), "");
+    let pam = Transaction::start(user, user, &[]);
+
+    assert_eq!(pam.authenticate(), PAM_SERVICE_ERR);
+    assert_eq!(pam.getenv("TOZNY_USER_ID"), None);
+}
+
+#[test]
+fn times_out_when_never_approved() {
+    let dir = match test_dir() { Some(dir) => dir, None => return };
+    let user = "tozny_never";
+    let api = MockApi::start(18203, "never", 0);
+    configure(&dir, user, &api, Some(&[MOCK_USER_ID]), "timeout=2");
+    let pam = Transaction::start(user, user, &[]);
+
+    assert_eq!(pam.authenticate(), PAM_AUTHINFO_UNAVAIL);
+    assert!(pam.transcript.contains(PAM_ERROR_MSG, "Timed out"));
+}
+
+#[test]
+fn rejects_malformed_login() {
+    let dir = match test_dir() { Some(dir) => dir, None => return };
+    let user = "tozny_malformed";
+    let api = MockApi::start(18204, "malformed", 0);
+    configure(&dir, user, &api, Some(&[MOCK_USER_ID]), "");
+    let pam = Transaction::start(user, user, &[]);
+
+    assert_eq!(pam.authenticate(), PAM_AUTH_ERR);
+    assert_eq!(pam.getenv("TOZNY_USER_ID"), None);
+}
+
+#[test]
+fn rejects_unauthorized_user() {
+    let dir = match test_dir() { Some(dir) => dir, None => return };
+    let user = "tozny_unauthorized";
+    let api = MockApi::start(18205, "approve", 0);
+    configure(&dir, user, &api, Some(&["sid_someone_else"]), "");
+    let pam = Transaction::start(user, user, &[]);
+
+    assert_eq!(pam.authenticate(), PAM_PERM_DENIED);
+    assert!(pam.transcript.contains(PAM_ERROR_MSG, "not authorized"));
+    assert_eq!(pam.getenv("TOZNY_USER_ID"), None);
+}
+
+#[test]
+fn requires_per_user_config() {
+    let dir = match test_dir() { Some(dir) => dir, None => return };
+    let user = "tozny_no_config";
+    let api = MockApi::start(18206, "approve", 0);
+    configure(&dir, user, &api, None, "");
+    let pam = Transaction::start(user, user, &[]);
+
+    assert_eq!(pam.authenticate(), PAM_AUTHINFO_UNAVAIL);
+    assert!(!home(&dir, user).join(".cache").exists());
+}
+
+#[test]
+fn lets_user_in_when_unavailable_with_success() {
+    let dir = match test_dir() { Some(dir) => dir, None => return };
+    let user = "tozny_unavailable_success";
+    let api = MockApi::start(18207, "approve", 0);
+    configure(&dir, user, &api, None, "on_unavailable=success");
+    let pam = Transaction::start(user, user, &[]);
+
+    assert_eq!(pam.authenticate(), PAM_SUCCESS);
+    assert_eq!(pam.getenv("TOZNY_USER_ID"), None);
+    // The account phase follows the decision of the auth phase.
+    assert_eq!(pam.acct_mgmt(), PAM_SUCCESS);
+}
+
+#[test]
+fn denies_when_unavailable_with_deny() {
+    let dir = match test_dir() { Some(dir) => dir, None => return };
+    let user = "tozny_unavailable_deny";
+    let api = MockApi::start(18208, "approve", 0);
+    configure(&dir, user, &api, None, "on_unavailable=deny");
+    let pam = Transaction::start(user, user, &[]);
+
+    assert_eq!(pam.authenticate(), PAM_AUTH_ERR);
+    assert!(pam.transcript.contains(PAM_ERROR_MSG, "No such file"));
+}
@@ -0,0 +1,34 @@
+#!/bin/sh
+#
+# Runs the end-to-end tests in tests/pam_module.rs.  The built module is loaded
+# through libpam, with pam_wrapper and nss_wrapper (from the cwrap project)
+# preloaded, so that no service files or accounts under /etc are needed or
+# touched.
+#
+# On Debian-based systems the wrappers are in the libpam-wrapper and
+# libnss-wrapper packages.
+
+set -e
+
+cd "$(dirname "$0")/.."
+
+cargo build
+cargo test --test pam_module --no-run
+
+dir="$PWD/target/pam_wrapper"
+rm -rf "$dir"
+mkdir -p "$dir/services"
+
+# The wrappers must not be preloaded into cargo and rustc, so the test binary is
+# run directly.
+test_bin=$(ls -t target/pam_module-* | grep -v '\.d$' | head -n 1)
+
+export TOZNY_PAM_TEST_DIR="$dir"
+export TOZNY_PAM_MODULE=$(ls -t "$PWD"/target/libtoznyauth_pam*.so | head -n 1)
+export TOZNY_MOCK_API="$PWD/target/tozny_mock_api"
+export PAM_WRAPPER=1
+export PAM_WRAPPER_SERVICE_DIR="$dir/services"
+export NSS_WRAPPER_PASSWD="$dir/passwd"
+export NSS_WRAPPER_GROUP="$dir/group"
+
+LD_PRELOAD="libpam_wrapper.so libnss_wrapper.so" exec "$test_bin" "$@"